@@ -0,0 +1,166 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! `#[derive(ResidentSize)]` for the `memory-lru` crate.
+//!
+//! The derived implementation sums the resident size of every field, and for
+//! enums of every field of the active variant. Fields accept two attributes:
+//!
+//! - `#[resident_size(skip)]` leaves the field out of the sum.
+//! - `#[resident_size(with = "path::to::fn")]` calls `fn(&field) -> usize`
+//!   instead of `ResidentSize::resident_size`, for types which do not
+//!   implement the trait.
+
+extern crate proc_macro;
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Error, Field, Fields, Index, LitStr, Path,
+    Result,
+};
+
+/// Derive `memory_lru::ResidentSize` by summing the resident size of fields.
+#[proc_macro_derive(ResidentSize, attributes(resident_size))]
+pub fn derive_resident_size(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> Result<TokenStream> {
+    let name = &input.ident;
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, sum) = destructure(&data.fields)?;
+            quote!(match *self { #name #pattern => #sum })
+        }
+        Data::Enum(data) => {
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let ident = &variant.ident;
+                    let (pattern, sum) = destructure(&variant.fields)?;
+                    Ok(quote!(#name::#ident #pattern => #sum,))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            // an empty enum has no values, so the match needs no arms.
+            quote!(match *self { #(#arms)* })
+        }
+        Data::Union(_) => {
+            return Err(Error::new(
+                Span::call_site(),
+                "ResidentSize cannot be derived for unions",
+            ))
+        }
+    };
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::memory_lru::ResidentSize));
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::memory_lru::ResidentSize for #name #ty_generics #where_clause {
+            fn resident_size(&self) -> usize {
+                #body
+            }
+        }
+    })
+}
+
+// build a pattern binding every field by reference, along with the
+// expression summing the sizes of the bound fields.
+fn destructure(fields: &Fields) -> Result<(TokenStream, TokenStream)> {
+    let mut bindings = Vec::new();
+    let mut sizes = Vec::new();
+
+    for (i, field) in fields.iter().enumerate() {
+        let binding = format_ident!("__field{}", i);
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = Index::from(i);
+                quote!(#index)
+            }
+        };
+
+        match FieldAttr::parse(field)? {
+            FieldAttr::Skip => bindings.push(quote!(#member: _)),
+            FieldAttr::With(path) => {
+                sizes.push(quote!(#path(#binding)));
+                bindings.push(quote!(#member: ref #binding));
+            }
+            FieldAttr::Default => {
+                sizes.push(quote!(::memory_lru::ResidentSize::resident_size(#binding)));
+                bindings.push(quote!(#member: ref #binding));
+            }
+        }
+    }
+
+    // braces work for named, tuple and unit variants alike.
+    let pattern = match fields {
+        Fields::Unit => quote!(),
+        _ => quote!({ #(#bindings),* }),
+    };
+
+    Ok((pattern, quote!(0usize #(+ #sizes)*)))
+}
+
+enum FieldAttr {
+    Default,
+    Skip,
+    With(Path),
+}
+
+impl FieldAttr {
+    fn parse(field: &Field) -> Result<Self> {
+        let mut parsed = FieldAttr::Default;
+
+        for attr in field.attrs.iter() {
+            if !attr.path().is_ident("resident_size") {
+                continue;
+            }
+
+            attr.parse_nested_meta(|meta| {
+                if !matches!(parsed, FieldAttr::Default) {
+                    return Err(meta.error("conflicting resident_size attributes"));
+                }
+
+                if meta.path.is_ident("skip") {
+                    parsed = FieldAttr::Skip;
+                    Ok(())
+                } else if meta.path.is_ident("with") {
+                    let path: LitStr = meta.value()?.parse()?;
+                    parsed = FieldAttr::With(path.parse()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `skip` or `with = \"...\"`"))
+                }
+            })?;
+        }
+
+        Ok(parsed)
+    }
+}
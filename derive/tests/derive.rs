@@ -0,0 +1,84 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use memory_lru::ResidentSize;
+
+struct Foreign(usize);
+
+fn foreign_size(foreign: &Foreign) -> usize {
+    foreign.0
+}
+
+#[derive(ResidentSize)]
+struct Node {
+    path: Vec<u8>,
+    children: Vec<Node>,
+    #[resident_size(skip)]
+    _scratch: Vec<u8>,
+    #[resident_size(with = "foreign_size")]
+    foreign: Foreign,
+}
+
+#[derive(ResidentSize)]
+struct Pair<T>(T, String);
+
+#[derive(ResidentSize)]
+struct Marker;
+
+#[derive(ResidentSize)]
+enum Value {
+    Empty,
+    Inline(u64),
+    Blob { data: Vec<u8>, meta: Option<String> },
+}
+
+#[test]
+fn sums_fields_recursively() {
+    let leaf = Node {
+        path: vec![0u8; 4],
+        children: Vec::new(),
+        _scratch: vec![0u8; 100],
+        foreign: Foreign(7),
+    };
+    assert_eq!(leaf.resident_size(), 11);
+
+    let root = Node {
+        path: vec![0u8; 2],
+        children: vec![leaf],
+        _scratch: Vec::new(),
+        foreign: Foreign(0),
+    };
+    assert_eq!(root.resident_size(), 2 + std::mem::size_of::<Node>() + 11);
+
+    assert_eq!(Pair(vec![0u8; 3], String::from("ab")).resident_size(), 5);
+    assert_eq!(Marker.resident_size(), 0);
+}
+
+#[test]
+fn sums_active_variant() {
+    assert_eq!(Value::Empty.resident_size(), 0);
+    assert_eq!(Value::Inline(5).resident_size(), 0);
+
+    let blob = Value::Blob {
+        data: vec![0u8; 10],
+        meta: Some(String::from("meta")),
+    };
+    assert_eq!(blob.resident_size(), 14);
+}
@@ -0,0 +1,317 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! A memory budget shared by several caches.
+
+use crate::concurrent::lock;
+use crate::{DefaultHasher, MemoryLruCache, Meter, ValueSize};
+
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, Weak};
+
+/// A combined ceiling on the memory used by several caches.
+///
+/// Caches are registered with the budget, each with a minimum size it is
+/// guaranteed to keep. Whenever an insertion takes the caches together over
+/// the budget, items are evicted from whichever cache holds the least
+/// recently used data until they fit again, skipping caches at or below
+/// their minimum. Each cache's own `max_size` still applies on top.
+///
+/// The budget compares caches by recency alone, so it evicts the least
+/// recently used unpinned item of the chosen cache even if the cache has an
+/// eviction policy of its own.
+///
+/// Handles are cheap to clone and all refer to the same budget.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    max_size: usize,
+    // stamps accesses across all member caches, so their items can be
+    // compared by recency.
+    ticks: Arc<AtomicU64>,
+    members: Mutex<Vec<Member>>,
+}
+
+struct Member {
+    cache: Weak<dyn Budgeted + Send + Sync>,
+    min_size: usize,
+}
+
+impl MemoryBudget {
+    /// Create a budget with a maximum cumulative size of entries across all
+    /// caches registered with it.
+    pub fn new(max_size: usize) -> Self {
+        MemoryBudget {
+            shared: Arc::new(Shared {
+                max_size,
+                ticks: Arc::new(AtomicU64::new(0)),
+                members: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Maximum cumulative size of entries across all caches.
+    pub fn max_size(&self) -> usize {
+        self.shared.max_size
+    }
+
+    /// Currently-used size of entries in bytes, across all caches.
+    pub fn current_size(&self) -> usize {
+        lock(&self.shared.members)
+            .iter()
+            .filter_map(|member| member.cache.upgrade())
+            .map(|cache| cache.size())
+            .sum()
+    }
+
+    /// Register a cache with the budget, guaranteeing it `min_size` bytes.
+    /// Items already in the cache count as the least recently used of all,
+    /// and are evicted straight away if the budget is exceeded.
+    pub fn register<K, V, M, S>(
+        &self,
+        mut cache: MemoryLruCache<K, V, M, S>,
+        min_size: usize,
+    ) -> BudgetedCache<K, V, M, S>
+    where
+        K: Eq + Hash + Send + 'static,
+        V: Send + 'static,
+        M: Meter<K, V> + Send + 'static,
+        S: BuildHasher + Send + 'static,
+    {
+        cache.register(self.shared.ticks.clone());
+        let cache = Arc::new(Mutex::new(cache));
+
+        let member: Arc<dyn Budgeted + Send + Sync> = cache.clone();
+        lock(&self.shared.members).push(Member {
+            cache: Arc::downgrade(&member),
+            min_size,
+        });
+
+        self.enforce();
+        BudgetedCache {
+            cache,
+            budget: self.clone(),
+        }
+    }
+
+    // evict the least recently used items across caches until they fit.
+    fn enforce(&self) {
+        let mut members = lock(&self.shared.members);
+        members.retain(|member| member.cache.strong_count() > 0);
+
+        let caches: Vec<_> = members
+            .iter()
+            .filter_map(|member| Some((member.cache.upgrade()?, member.min_size)))
+            .collect();
+        // caches which may still have an item to evict.
+        let mut candidates: Vec<_> = caches.iter().collect();
+
+        // member caches are locked one at a time, and `BudgetedCache` releases
+        // its own lock before enforcing the budget.
+        while caches.iter().map(|(cache, _)| cache.size()).sum::<usize>() > self.shared.max_size {
+            // a cache may only give up items while it stays at its minimum.
+            let coldest = candidates
+                .iter()
+                .enumerate()
+                .filter_map(|(i, (cache, min_size))| {
+                    let (tick, charge) = cache.coldest()?;
+                    if cache.size() < min_size + charge {
+                        return None;
+                    }
+                    Some((tick, i))
+                })
+                .min_by_key(|(tick, _)| *tick);
+
+            let i = match coldest {
+                Some((_, i)) => i,
+                None => break,
+            };
+            if !candidates[i].0.evict() {
+                candidates.swap_remove(i);
+            }
+        }
+    }
+}
+
+// a member cache, with its types erased.
+trait Budgeted {
+    fn size(&self) -> usize;
+    fn coldest(&self) -> Option<(u64, usize)>;
+    fn evict(&self) -> bool;
+}
+
+impl<K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> Budgeted
+    for Mutex<MemoryLruCache<K, V, M, S>>
+{
+    fn size(&self) -> usize {
+        lock(self).current_size()
+    }
+
+    fn coldest(&self) -> Option<(u64, usize)> {
+        lock(self).coldest()
+    }
+
+    fn evict(&self) -> bool {
+        lock(self).evict_for_budget()
+    }
+}
+
+/// A cache registered with a `MemoryBudget`, created by
+/// `MemoryBudget::register`. Dropping it frees its share of the budget.
+pub struct BudgetedCache<K, V, M = ValueSize, S = DefaultHasher> {
+    cache: Arc<Mutex<MemoryLruCache<K, V, M, S>>>,
+    budget: MemoryBudget,
+}
+
+impl<K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> BudgetedCache<K, V, M, S> {
+    /// Insert an item, then enforce the shared budget.
+    pub fn insert(&self, key: K, val: V) {
+        self.with_cache(|cache| cache.insert(key, val))
+    }
+
+    /// Get a copy of an item in the cache, updating its recency.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        lock(&self.cache).get(key).cloned()
+    }
+
+    /// Remove an item from the cache, returning its value if it was present.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        lock(&self.cache).remove(key)
+    }
+
+    /// Run a closure with exclusive access to the cache, then enforce the
+    /// shared budget.
+    pub fn with_cache<U>(&self, f: impl FnOnce(&mut MemoryLruCache<K, V, M, S>) -> U) -> U {
+        let res = f(&mut lock(&self.cache));
+        self.budget.enforce();
+        res
+    }
+
+    /// Currently-used size of entries in bytes, as measured by the meter.
+    pub fn current_size(&self) -> usize {
+        lock(&self.cache).current_size()
+    }
+
+    /// Returns the number of key-value pairs that are currently in the cache.
+    pub fn len(&self) -> usize {
+        lock(&self.cache).len()
+    }
+
+    /// Returns a bool indicating whether the cache is empty or not.
+    pub fn is_empty(&self) -> bool {
+        lock(&self.cache).is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SharedArcSize;
+
+    #[test]
+    fn coldest_data_is_evicted_across_caches() {
+        let budget = MemoryBudget::new(100);
+        let a = budget.register(MemoryLruCache::new(100), 0);
+        let b = budget.register(MemoryLruCache::new(100), 0);
+
+        a.insert(1, vec![0u8; 40]);
+        a.insert(2, vec![0u8; 40]);
+        b.insert(1, vec![0u8; 30]);
+        assert_eq!(a.get(&1), None);
+        assert_eq!(budget.current_size(), 70);
+
+        // 2 in `a` is now fresher than 1 in `b`.
+        a.get(&2);
+        b.insert(2, vec![0u8; 30]);
+        b.insert(3, vec![0u8; 20]);
+        assert!(a.get(&2).is_some());
+        assert_eq!(b.get(&1), None);
+        assert_eq!(budget.current_size(), 90);
+
+        drop(a);
+        assert_eq!(budget.current_size(), 50);
+    }
+
+    #[test]
+    fn pinned_items_do_not_stall_enforcement() {
+        let budget = MemoryBudget::new(100);
+        let a = budget.register(MemoryLruCache::with_meter(100, SharedArcSize), 0);
+        let b = budget.register(MemoryLruCache::new(100), 0);
+
+        a.with_cache(|cache| {
+            cache.insert(1, Arc::new(vec![0u8; 56]));
+            cache.pin(&1).unwrap();
+        });
+        b.insert(1, vec![0u8; 15]);
+        b.insert(2, vec![0u8; 15]);
+
+        // `a` holds the coldest item, but only `b` can give anything up.
+        assert_eq!(a.current_size(), 24 + 56);
+        assert_eq!(b.get(&1), None);
+        assert_eq!(budget.current_size(), 95);
+    }
+
+    #[test]
+    fn minimum_sizes_are_guaranteed() {
+        let budget = MemoryBudget::new(100);
+        let a = budget.register(MemoryLruCache::new(100), 60);
+        let b = budget.register(MemoryLruCache::new(100), 0);
+
+        a.insert(1, vec![0u8; 30]);
+        a.insert(2, vec![0u8; 30]);
+        b.insert(1, vec![0u8; 30]);
+        b.insert(2, vec![0u8; 30]);
+
+        assert_eq!(a.current_size(), 60);
+        assert_eq!(b.current_size(), 30);
+        assert_eq!(b.get(&1), None);
+    }
+
+    #[test]
+    fn minimum_sizes_hold_between_item_sizes() {
+        let budget = MemoryBudget::new(100);
+        let a = budget.register(MemoryLruCache::new(100), 50);
+        let b = budget.register(MemoryLruCache::new(100), 0);
+
+        a.insert(1, vec![0u8; 40]);
+        a.insert(2, vec![0u8; 30]);
+        b.insert(1, vec![0u8; 20]);
+        b.insert(2, vec![0u8; 20]);
+
+        // evicting the coldest item from `a` would take it to 30 bytes.
+        assert_eq!(a.current_size(), 70);
+        assert_eq!(b.current_size(), 20);
+        assert_eq!(b.get(&1), None);
+    }
+}
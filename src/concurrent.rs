@@ -0,0 +1,240 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! A thread-safe memory-based LRU cache, sharded to reduce lock contention.
+
+use crate::{EvictionListener, MemoryLruCache, Meter, ResidentSize, ValueSize};
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+const DEFAULT_SHARDS: usize = 16;
+
+/// A `MemoryLruCache` which can be shared between threads.
+///
+/// The memory budget is split evenly across a number of shards, each guarded
+/// by its own lock. Keys are assigned to shards by hash, so recency is only
+/// tracked within a shard.
+pub struct ConcurrentMemoryLruCache<K, V, M = ValueSize> {
+    shards: Box<[Mutex<MemoryLruCache<K, V, M>>]>,
+    hasher: RandomState,
+    cur_size: AtomicUsize,
+    max_size: usize,
+}
+
+impl<K: Eq + Hash, V: ResidentSize> ConcurrentMemoryLruCache<K, V> {
+    /// Create a new cache with a maximum cumulative size of values, split
+    /// across the default number of shards.
+    pub fn new(max_size: usize) -> Self {
+        ConcurrentMemoryLruCache::with_shards(max_size, DEFAULT_SHARDS)
+    }
+
+    /// Create a new cache with a maximum cumulative size of values, split
+    /// across the given number of shards.
+    pub fn with_shards(max_size: usize, shards: usize) -> Self {
+        ConcurrentMemoryLruCache::with_meter(max_size, shards, ValueSize)
+    }
+}
+
+impl<K: Eq + Hash, V, M: Meter<K, V> + Clone> ConcurrentMemoryLruCache<K, V, M> {
+    /// Create a new cache with a maximum cumulative size of entries, as
+    /// measured by the given meter, split across the given number of shards.
+    pub fn with_meter(max_size: usize, shards: usize, meter: M) -> Self {
+        let shards = shards.max(1);
+        let shards = (0..shards)
+            .map(|i| {
+                // hand out the remainder one byte at a time so the shard
+                // budgets add up to exactly `max_size`.
+                let extra = (i < max_size % shards) as usize;
+                Mutex::new(MemoryLruCache::with_meter(
+                    max_size / shards + extra,
+                    meter.clone(),
+                ))
+            })
+            .collect();
+
+        ConcurrentMemoryLruCache {
+            shards,
+            hasher: RandomState::new(),
+            cur_size: AtomicUsize::new(0),
+            max_size,
+        }
+    }
+
+    /// Set the listener notified of every entry any shard drops. Each shard
+    /// receives its own clone of the listener.
+    pub fn set_eviction_listener(
+        &self,
+        listener: impl EvictionListener<K, V> + Clone + Send + 'static,
+    ) {
+        for shard in self.shards.iter() {
+            lock(shard).set_eviction_listener(listener.clone());
+        }
+    }
+
+    /// Insert an item.
+    pub fn insert(&self, key: K, val: V) {
+        let shard = self.shard(&key);
+        self.with_shard(shard, |cache| cache.insert(key, val))
+    }
+
+    /// Get a copy of an item in the cache, updating its recency.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        self.with_shard(self.shard(key), |cache| cache.get(key).cloned())
+    }
+
+    /// Execute a closure with the value under the provided key. The shard
+    /// holding the key is locked for the duration of the closure.
+    pub fn with_mut<Q, U>(&self, key: &Q, with: impl FnOnce(Option<&mut V>) -> U) -> U
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.with_shard(self.shard(key), |cache| cache.with_mut(key, with))
+    }
+
+    /// Get a copy of an item in the cache without updating its recency.
+    pub fn peek<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        lock(&self.shards[self.shard(key)]).peek(key).cloned()
+    }
+
+    /// Returns a bool indicating whether the given key is in the cache.
+    /// Does not update the LRU list.
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        lock(&self.shards[self.shard(key)]).contains(key)
+    }
+
+    /// Currently-used size of entries in bytes, across all shards.
+    pub fn current_size(&self) -> usize {
+        self.cur_size.load(Ordering::Acquire)
+    }
+
+    /// The maximum cumulative size of entries, across all shards.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Returns the number of key-value pairs that are currently in the cache.
+    /// Each shard is locked in turn, so the result may be stale under
+    /// concurrent modification.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| lock(shard).len()).sum()
+    }
+
+    /// Returns a bool indicating whether the cache is empty or not.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| lock(shard).is_empty())
+    }
+
+    // `Borrow` guarantees the borrowed form hashes like the key itself, so
+    // both land on the same shard.
+    fn shard<Q: ?Sized + Hash>(&self, key: &Q) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    // run an operation which may change the size of a shard, keeping the
+    // global size in step while the shard is still locked.
+    fn with_shard<U>(&self, shard: usize, f: impl FnOnce(&mut MemoryLruCache<K, V, M>) -> U) -> U {
+        let mut cache = lock(&self.shards[shard]);
+        let prev_size = cache.current_size();
+
+        let res = f(&mut cache);
+
+        let new_size = cache.current_size();
+        if new_size > prev_size {
+            self.cur_size
+                .fetch_add(new_size - prev_size, Ordering::AcqRel);
+        } else {
+            self.cur_size
+                .fetch_sub(prev_size - new_size, Ordering::AcqRel);
+        }
+
+        res
+    }
+}
+
+// the cache is left consistent if a `with_mut` closure panics, so a poisoned
+// lock is safe to reuse.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn it_works() {
+        // each shard gets 64 bytes, enough for both values wherever they land.
+        let cache = ConcurrentMemoryLruCache::with_shards(256, 4);
+        cache.insert("hello", vec![0u8; 30]);
+        cache.insert("world", vec![0u8; 20]);
+
+        assert_eq!(cache.get(&"hello"), Some(vec![0u8; 30]));
+        assert_eq!(cache.current_size(), 50);
+        assert_eq!(cache.len(), 2);
+
+        cache.with_mut(&"world", |v| v.unwrap().push(1));
+        assert_eq!(cache.peek(&"world").map(|v| v.len()), Some(21));
+        assert_eq!(cache.current_size(), 51);
+    }
+
+    #[test]
+    fn size_stays_accurate_under_concurrent_eviction() {
+        let cache = ConcurrentMemoryLruCache::with_shards(1000, 8);
+
+        thread::scope(|s| {
+            for t in 0..8u32 {
+                let cache = &cache;
+                s.spawn(move || {
+                    for i in 0..500u32 {
+                        cache.insert((t, i), vec![0u8; (i % 30) as usize]);
+                    }
+                });
+            }
+        });
+
+        let sum: usize = cache
+            .shards
+            .iter()
+            .map(|shard| lock(shard).current_size())
+            .sum();
+        assert_eq!(cache.current_size(), sum);
+        assert!(cache.current_size() <= cache.max_size());
+    }
+}
@@ -0,0 +1,352 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! In-place manipulation of cache items, created by `MemoryLruCache::entry`.
+//!
+//! Both looking up an occupied entry and inserting into a vacant one make the
+//! item the most recently used, and it stays that way for as long as the
+//! entry or the `ValueMut` guard derived from it borrows the cache. The
+//! item's size is recomputed, and the budget enforced, once that borrow ends.
+//! Being the most recently used, the item is evicted only after every other
+//! unpinned item.
+
+use crate::{MemoryLruCache, Meter, OversizedError};
+
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+
+/// A view into a single entry of the cache, which may be occupied or vacant.
+pub enum Entry<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> {
+    /// An entry holding an item.
+    Occupied(OccupiedEntry<'a, K, V, M, S>),
+    /// An entry with no item.
+    Vacant(VacantEntry<'a, K, V, M, S>),
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> Entry<'a, K, V, M, S> {
+    /// The key of the entry.
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Insert the given value if the entry is vacant, and return a guard for
+    /// the entry's value.
+    pub fn or_insert(self, default: V) -> ValueMut<'a, K, V, M, S> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Insert the result of the closure if the entry is vacant, and return a
+    /// guard for the entry's value.
+    pub fn or_insert_with(self, default: impl FnOnce() -> V) -> ValueMut<'a, K, V, M, S> {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Insert the result of the fallible closure if the entry is vacant, and
+    /// return a guard for the entry's value. Any error is returned and
+    /// nothing is inserted.
+    pub fn or_try_insert_with<E>(
+        self,
+        default: impl FnOnce() -> Result<V, E>,
+    ) -> Result<ValueMut<'a, K, V, M, S>, E> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(default()?)),
+        }
+    }
+
+    /// Modify the value in place if the entry is occupied.
+    pub fn and_modify(mut self, modify: impl FnOnce(&mut V)) -> Self {
+        if let Entry::Occupied(entry) = &mut self {
+            modify(entry.get_mut());
+        }
+        self
+    }
+}
+
+/// An occupied entry of the cache.
+pub struct OccupiedEntry<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> {
+    pub(crate) key: K,
+    pub(crate) value: ValueMut<'a, K, V, M, S>,
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> OccupiedEntry<'a, K, V, M, S> {
+    /// The key of the entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Get a reference to the value.
+    pub fn get(&self) -> &V {
+        &self.value
+    }
+
+    /// Get a mutable reference to the value.
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.value
+    }
+
+    /// Convert the entry into a guard for its value.
+    pub fn into_mut(self) -> ValueMut<'a, K, V, M, S> {
+        self.value
+    }
+
+    /// Replace the value, returning the old one.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(&mut self.value, value)
+    }
+
+    /// Remove the item from the cache, returning its value.
+    pub fn remove(mut self) -> V {
+        self.value.released = true;
+        self.value
+            .cache
+            .remove(&self.key)
+            .expect("occupied entries are only created for keys in the cache; qed")
+    }
+}
+
+/// A vacant entry of the cache.
+pub struct VacantEntry<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> {
+    pub(crate) cache: &'a mut MemoryLruCache<K, V, M, S>,
+    pub(crate) key: K,
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> VacantEntry<'a, K, V, M, S> {
+    /// The key of the entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Take back ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Insert a value, expiring after the cache's default time-to-live if one
+    /// is set, and return a guard for it. Should the value be larger than the
+    /// whole memory budget once the guard is dropped, it is handled according
+    /// to the cache's oversize policy.
+    pub fn insert(self, value: V) -> ValueMut<'a, K, V, M, S> {
+        let size = self.cache.meter.measure(&self.key, &value);
+        self.insert_sized(value, size)
+    }
+
+    /// Insert a value like `insert`, failing if it is larger than the whole
+    /// memory budget. On failure the item is handed back and the cache is
+    /// left untouched.
+    pub fn try_insert(self, value: V) -> Result<ValueMut<'a, K, V, M, S>, OversizedError<K, V>> {
+        let (key, value, size) = self.cache.fit(self.key, value)?;
+        let entry = VacantEntry {
+            cache: self.cache,
+            key,
+        };
+        Ok(entry.insert_sized(value, size))
+    }
+
+    fn insert_sized(self, value: V, size: usize) -> ValueMut<'a, K, V, M, S> {
+        let expires = self.cache.default_deadline();
+        // the entry is vacant, so nothing can be displaced.
+        self.cache.push_slot(self.key, value, size, expires);
+        let mut value = ValueMut::new(self.cache);
+        value.inserted = true;
+        value
+    }
+}
+
+/// A guard giving mutable access to the most recently used value of the
+/// cache. The value's size is recomputed when the guard is dropped, evicting
+/// items if the cache has outgrown its memory budget. Guards from
+/// `MemoryLruCache::get_mut` keep their own item even then.
+pub struct ValueMut<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> {
+    cache: &'a mut MemoryLruCache<K, V, M, S>,
+    released: bool,
+    spare: bool,
+    inserted: bool,
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> ValueMut<'a, K, V, M, S> {
+    // the caller must have just made the item most recently used.
+    pub(crate) fn new(cache: &'a mut MemoryLruCache<K, V, M, S>) -> Self {
+        ValueMut {
+            cache,
+            released: false,
+            spare: false,
+            inserted: false,
+        }
+    }
+
+    // like `new`, but the item is kept when the guard is dropped.
+    pub(crate) fn sparing(cache: &'a mut MemoryLruCache<K, V, M, S>) -> Self {
+        let mut value = ValueMut::new(cache);
+        value.spare = true;
+        value
+    }
+
+    /// Let the guarded item be evicted when the guard is dropped, should the
+    /// cache still be over budget once no other item can go. Only guards from
+    /// `MemoryLruCache::get_mut` keep their item by default.
+    pub fn allow_eviction(&mut self) {
+        self.spare = false;
+    }
+
+    /// The key of the guarded item.
+    pub fn key(&self) -> &K {
+        self.cache
+            .inner
+            .iter()
+            .next()
+            .map(|(k, _)| k)
+            .expect("guarded item stays most recently used while borrowed; qed")
+    }
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> Deref for ValueMut<'a, K, V, M, S> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.cache
+            .inner
+            .iter()
+            .next()
+            .map(|(_, slot)| &slot.value)
+            .expect("guarded item stays most recently used while borrowed; qed")
+    }
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> DerefMut for ValueMut<'a, K, V, M, S> {
+    fn deref_mut(&mut self) -> &mut V {
+        self.cache
+            .inner
+            .iter_mut()
+            .next()
+            .map(|(_, slot)| &mut slot.value)
+            .expect("guarded item stays most recently used while borrowed; qed")
+    }
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> Drop for ValueMut<'a, K, V, M, S> {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+
+        if self.inserted {
+            self.cache.release_inserted();
+        } else {
+            self.cache.release_mru(self.spare);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Entry, MemoryLruCache, OversizePolicy};
+
+    #[test]
+    fn or_insert_with_accounts_on_release() {
+        let mut cache = MemoryLruCache::new(10);
+        cache.insert(1, vec![0u8; 4]);
+
+        cache.entry(2).or_insert_with(Vec::new).extend([1u8; 3]);
+        assert_eq!(cache.current_size(), 7);
+
+        // the existing value is kept and grown in place, pushing 1 out.
+        {
+            let mut v = cache.entry(2).or_insert(vec![9u8; 100]);
+            assert_eq!(*v, vec![1u8; 3]);
+            v.extend([2u8; 5]);
+        }
+        assert_eq!(cache.current_size(), 8);
+        assert!(!cache.contains(&1));
+
+        // an item outgrowing the whole budget goes too.
+        cache.entry(2).or_insert_with(Vec::new).extend([0u8; 3]);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn and_modify_and_occupied_operations() {
+        let mut cache = MemoryLruCache::new(100);
+        cache.insert("a", vec![0u8; 4]);
+        cache.insert("b", vec![0u8; 2]);
+
+        cache
+            .entry("a")
+            .and_modify(|v| v.push(1))
+            .or_insert_with(Vec::new);
+        assert_eq!(cache.current_size(), 7);
+        assert_eq!(cache.keys().next(), Some(&"a"));
+
+        match cache.entry("b") {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.insert(vec![0u8; 8]), vec![0u8; 2]);
+            }
+            Entry::Vacant(_) => unreachable!(),
+        }
+        assert_eq!(cache.current_size(), 13);
+
+        match cache.entry("a") {
+            Entry::Occupied(entry) => assert_eq!(entry.remove().len(), 5),
+            Entry::Vacant(_) => unreachable!(),
+        }
+        assert_eq!(cache.current_size(), 8);
+        assert!(matches!(cache.entry("a"), Entry::Vacant(_)));
+    }
+
+    #[test]
+    fn vacant_insertion_follows_oversize_policy() {
+        let mut cache = MemoryLruCache::new(10);
+        cache.set_oversize_policy(OversizePolicy::Reject);
+        cache.insert(1, vec![0u8; 4]);
+
+        cache.entry(2).or_insert(vec![0u8; 100]);
+        cache.entry(3).or_insert_with(Vec::new).extend([0u8; 20]);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(cache.current_size(), 4);
+
+        match cache.entry(2) {
+            Entry::Vacant(entry) => assert_eq!(
+                entry.try_insert(vec![0u8; 11]).err().map(|e| e.size),
+                Some(11)
+            ),
+            Entry::Occupied(_) => unreachable!(),
+        }
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn failed_insertion_leaves_cache_untouched() {
+        let mut cache = MemoryLruCache::new(100);
+        let res = cache.get_or_try_insert_with("a", || Err::<Vec<u8>, _>("unavailable"));
+        assert_eq!(res.err(), Some("unavailable"));
+        assert!(cache.is_empty());
+
+        let v = cache.get_or_try_insert_with("a", || Ok::<_, ()>(vec![0u8; 3]));
+        assert_eq!(v.unwrap().len(), 3);
+        assert_eq!(cache.current_size(), 3);
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Time sources for expiring cache items.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A source of the current time, used to decide when items expire.
+pub trait Clock {
+    /// Return the current instant. Successive calls must not go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EvictionReason, MemoryLruCache};
+
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn expired_items_are_absent() {
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let mut cache = MemoryLruCache::new(256);
+        cache.set_clock(clock.clone());
+        cache.set_default_ttl(Some(Duration::from_secs(10)));
+
+        cache.insert("a", vec![0u8; 10]);
+        cache.insert_with_ttl("b", vec![0u8; 20], Duration::from_secs(5));
+        clock.advance(Duration::from_secs(5));
+
+        assert!(!cache.contains(&"b"));
+        assert!(cache.peek(&"b").is_none());
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&"b").is_none());
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.current_size(), 10);
+
+        assert_eq!(cache.get(&"a"), Some(&vec![0u8; 10]));
+        clock.advance(Duration::from_secs(5));
+        assert!(cache.with_mut(&"a", |v| v.is_none()));
+    }
+
+    #[test]
+    fn purge_reclaims_expired_items() {
+        let clock = Arc::new(ManualClock(Mutex::new(Instant::now())));
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = MemoryLruCache::new(256);
+        cache.set_clock(clock.clone());
+        cache.set_eviction_listener({
+            let expired = expired.clone();
+            move |k, _: Vec<u8>, reason| {
+                assert_eq!(reason, EvictionReason::Expired);
+                expired.lock().unwrap().push(k);
+            }
+        });
+
+        cache.insert_with_ttl(1, vec![0u8; 1], Duration::from_secs(1));
+        cache.insert(2, vec![0u8; 2]);
+        cache.insert_with_ttl(3, vec![0u8; 3], Duration::from_secs(3));
+        cache.insert_with_ttl(4, vec![0u8; 4], Duration::from_secs(1));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(cache.peek_lru().map(|(k, _)| *k), Some(2));
+
+        cache.purge_expired();
+        assert_eq!(*expired.lock().unwrap(), vec![1, 4]);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(cache.current_size(), 5);
+    }
+}
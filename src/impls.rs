@@ -0,0 +1,209 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Implementations of `ResidentSize` for standard library types.
+//!
+//! These count the heap memory reachable from a value, not the inline size of
+//! the value itself. Collections count the elements they hold rather than
+//! their spare capacity, so sizes do not jump with allocator growth.
+
+use crate::ResidentSize;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+
+macro_rules! impl_inline {
+    ($($t:ty),*) => {
+        $(
+            impl ResidentSize for $t {
+                fn resident_size(&self) -> usize {
+                    0
+                }
+            }
+        )*
+    };
+}
+
+impl_inline!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+
+macro_rules! impl_tuple {
+    ($($t:ident),+) => {
+        impl<$($t: ResidentSize),+> ResidentSize for ($($t,)+) {
+            #[allow(non_snake_case)]
+            fn resident_size(&self) -> usize {
+                let ($($t,)+) = self;
+                0 $(+ $t.resident_size())+
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+impl_tuple!(A, B, C, D, E, F);
+impl_tuple!(A, B, C, D, E, F, G);
+impl_tuple!(A, B, C, D, E, F, G, H);
+
+impl ResidentSize for str {
+    fn resident_size(&self) -> usize {
+        0
+    }
+}
+
+impl<T: ResidentSize> ResidentSize for [T] {
+    fn resident_size(&self) -> usize {
+        self.iter().map(ResidentSize::resident_size).sum()
+    }
+}
+
+impl<T: ResidentSize, const N: usize> ResidentSize for [T; N] {
+    fn resident_size(&self) -> usize {
+        self[..].resident_size()
+    }
+}
+
+impl<T: ResidentSize> ResidentSize for Option<T> {
+    fn resident_size(&self) -> usize {
+        self.as_ref().map_or(0, ResidentSize::resident_size)
+    }
+}
+
+impl ResidentSize for String {
+    fn resident_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T: ResidentSize> ResidentSize for Vec<T> {
+    fn resident_size(&self) -> usize {
+        self.len() * mem::size_of::<T>() + self[..].resident_size()
+    }
+}
+
+impl<T: ResidentSize> ResidentSize for VecDeque<T> {
+    fn resident_size(&self) -> usize {
+        self.len() * mem::size_of::<T>()
+            + self.iter().map(ResidentSize::resident_size).sum::<usize>()
+    }
+}
+
+impl<T: ResidentSize + ?Sized> ResidentSize for Box<T> {
+    fn resident_size(&self) -> usize {
+        mem::size_of_val::<T>(self) + (**self).resident_size()
+    }
+}
+
+/// Counts the whole shared allocation, as though this handle owned it.
+impl<T: ResidentSize + ?Sized> ResidentSize for Arc<T> {
+    fn resident_size(&self) -> usize {
+        mem::size_of_val::<T>(self) + (**self).resident_size()
+    }
+}
+
+/// Counts the whole shared allocation, as though this handle owned it.
+impl<T: ResidentSize + ?Sized> ResidentSize for Rc<T> {
+    fn resident_size(&self) -> usize {
+        mem::size_of_val::<T>(self) + (**self).resident_size()
+    }
+}
+
+/// Counts the inline size of every key and value plus their own resident
+/// size. The table's control bytes and empty buckets are not counted.
+impl<K: ResidentSize, V: ResidentSize, S> ResidentSize for HashMap<K, V, S> {
+    fn resident_size(&self) -> usize {
+        self.len() * (mem::size_of::<K>() + mem::size_of::<V>())
+            + self
+                .iter()
+                .map(|(k, v)| k.resident_size() + v.resident_size())
+                .sum::<usize>()
+    }
+}
+
+/// Counts the inline size of every key and value plus their own resident
+/// size. The tree's node overhead is not counted.
+impl<K: ResidentSize, V: ResidentSize> ResidentSize for BTreeMap<K, V> {
+    fn resident_size(&self) -> usize {
+        self.len() * (mem::size_of::<K>() + mem::size_of::<V>())
+            + self
+                .iter()
+                .map(|(k, v)| k.resident_size() + v.resident_size())
+                .sum::<usize>()
+    }
+}
+
+/// Counts the length of the view, which may share its buffer with others.
+#[cfg(feature = "bytes")]
+impl ResidentSize for bytes::Bytes {
+    fn resident_size(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl ResidentSize for bytes::BytesMut {
+    fn resident_size(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_heap_memory() {
+        assert_eq!(5u64.resident_size(), 0);
+        assert_eq!(vec![0u8; 10].resident_size(), 10);
+        assert_eq!(vec![0u64; 10].resident_size(), 80);
+        assert_eq!(vec![vec![0u8; 3]; 2].resident_size(), 2 * 24 + 6);
+        assert_eq!(String::from("hello").resident_size(), 5);
+        assert_eq!(Box::new(7u32).resident_size(), 4);
+        assert_eq!(Arc::new(vec![0u8; 4]).resident_size(), 24 + 4);
+        assert_eq!(Some(vec![0u8; 4]).resident_size(), 4);
+        assert_eq!((vec![0u8; 1], String::from("ab")).resident_size(), 3);
+        assert_eq!([vec![0u8; 2], vec![0u8; 3]].resident_size(), 5);
+
+        let map: HashMap<u32, Vec<u8>> = (0..3).map(|i| (i, vec![0u8; 2])).collect();
+        assert_eq!(map.resident_size(), 3 * (4 + 24) + 6);
+    }
+}
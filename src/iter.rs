@@ -0,0 +1,290 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Iterators over the items of a `MemoryLruCache`.
+//!
+//! Unless noted otherwise, items are visited from most to least recently
+//! used, and every iterator can be reversed to visit them the other way.
+
+use crate::{recharge, MemoryLruCache, Meter, Slot};
+
+use std::hash::{BuildHasher, Hash};
+use std::iter::{FusedIterator, Rev};
+
+/// An iterator over the items of a cache, created by `MemoryLruCache::iter`.
+pub struct Iter<'a, K, V> {
+    pub(crate) inner: lru::Iter<'a, K, Slot<V>>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.inner.next().map(|(k, slot)| (k, &slot.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        self.inner.next_back().map(|(k, slot)| (k, &slot.value))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+impl<'a, K, V> FusedIterator for Iter<'a, K, V> {}
+
+/// An iterator over the keys of a cache, created by `MemoryLruCache::keys`.
+pub struct Keys<'a, K, V> {
+    pub(crate) inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Keys<'a, K, V> {
+    fn next_back(&mut self) -> Option<&'a K> {
+        self.inner.next_back().map(|(k, _)| k)
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {}
+impl<'a, K, V> FusedIterator for Keys<'a, K, V> {}
+
+/// An iterator over the values of a cache, created by
+/// `MemoryLruCache::values`.
+pub struct Values<'a, K, V> {
+    pub(crate) inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Values<'a, K, V> {
+    fn next_back(&mut self) -> Option<&'a V> {
+        self.inner.next_back().map(|(_, v)| v)
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Values<'a, K, V> {}
+impl<'a, K, V> FusedIterator for Values<'a, K, V> {}
+
+/// A guard giving mutable access to the values of a cache, created by
+/// `MemoryLruCache::iter_mut`. Iterate over `&mut` the guard to visit the
+/// items; their sizes are recomputed when the guard is dropped.
+pub struct IterMut<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> {
+    pub(crate) cache: &'a mut MemoryLruCache<K, V, M, S>,
+}
+
+impl<'a, 'b, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> IntoIterator
+    for &'b mut IterMut<'a, K, V, M, S>
+{
+    type Item = (&'b K, &'b mut V);
+    type IntoIter = EntriesMut<'b, K, V>;
+
+    fn into_iter(self) -> EntriesMut<'b, K, V> {
+        EntriesMut {
+            inner: self.cache.inner.iter_mut(),
+        }
+    }
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> Drop for IterMut<'a, K, V, M, S> {
+    fn drop(&mut self) {
+        let cache = &mut *self.cache;
+        for (k, slot) in cache.inner.iter_mut() {
+            let new_size = cache.meter.measure(k, &slot.value);
+            let new_share = cache.meter.shared(k, &slot.value);
+            recharge(
+                slot,
+                new_size,
+                new_share,
+                &mut cache.cur_size,
+                &mut cache.pinned_size,
+                &mut cache.shares,
+                cache.max_size,
+            );
+            if let Some(policy) = cache.policy.as_mut() {
+                policy.on_resize(k, new_size);
+            }
+        }
+
+        cache.readjust_down();
+    }
+}
+
+/// An iterator over the items of a cache with mutable access to values,
+/// created from an `IterMut` guard.
+pub struct EntriesMut<'a, K, V> {
+    inner: lru::IterMut<'a, K, Slot<V>>,
+}
+
+impl<'a, K, V> Iterator for EntriesMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        self.inner.next().map(|(k, slot)| (k, &mut slot.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for EntriesMut<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a mut V)> {
+        self.inner.next_back().map(|(k, slot)| (k, &mut slot.value))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for EntriesMut<'a, K, V> {}
+impl<'a, K, V> FusedIterator for EntriesMut<'a, K, V> {}
+
+/// An owning iterator over the items of a cache, created by
+/// `MemoryLruCache::into_iter`.
+pub struct IntoIter<K, V> {
+    pub(crate) inner: Rev<std::vec::IntoIter<(K, V)>>,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IntoIter<K, V> {
+    fn next_back(&mut self) -> Option<(K, V)> {
+        self.inner.next_back()
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {}
+impl<K, V> FusedIterator for IntoIter<K, V> {}
+
+/// A draining iterator over the items of a cache, created by
+/// `MemoryLruCache::drain`. Unlike the other iterators, items are yielded
+/// from least to most recently used.
+pub struct Drain<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> {
+    pub(crate) cache: &'a mut MemoryLruCache<K, V, M, S>,
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> Iterator for Drain<'a, K, V, M, S> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.cache.pop_lru()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.cache.len();
+        (len, Some(len))
+    }
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> ExactSizeIterator
+    for Drain<'a, K, V, M, S>
+{
+}
+
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> Drop for Drain<'a, K, V, M, S> {
+    fn drop(&mut self) {
+        self.cache.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryLruCache;
+
+    #[test]
+    fn iterates_in_recency_order() {
+        let mut cache = MemoryLruCache::new(256);
+        for i in 0..4u8 {
+            cache.insert(i, vec![i; 2]);
+        }
+        cache.get(&1);
+
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec![1, 3, 2, 0]);
+        assert_eq!(
+            cache.keys().rev().copied().collect::<Vec<_>>(),
+            vec![0, 2, 3, 1]
+        );
+        assert_eq!(cache.values().next(), Some(&vec![1u8; 2]));
+        assert_eq!((&cache).into_iter().len(), 4);
+
+        let owned: Vec<_> = cache.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(owned, vec![1, 3, 2, 0]);
+    }
+
+    #[test]
+    fn iter_mut_reaccounts_on_drop() {
+        let mut cache = MemoryLruCache::new(10);
+        for i in 0..4u8 {
+            cache.insert(i, vec![i; 2]);
+        }
+
+        for (k, v) in &mut cache.iter_mut() {
+            if *k == 0 {
+                v.clear();
+            } else {
+                v.push(*k);
+            }
+        }
+
+        // 0 + 3 + 3 + 3 fits, and nothing was evicted.
+        assert_eq!(cache.current_size(), 9);
+        assert_eq!(cache.len(), 4);
+
+        for (_, v) in (&mut cache.iter_mut()).into_iter().rev().take(2) {
+            v.push(0);
+        }
+
+        // 1 + 4 + 3 + 3 is too big, so the least recently used item goes.
+        assert_eq!(cache.current_size(), 10);
+        assert_eq!(cache.peek_lru(), Some((&1, &vec![1u8, 1, 1, 0])));
+    }
+}
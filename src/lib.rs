@@ -20,39 +20,716 @@
 
 //! A memory-based LRU cache.
 
+pub mod budget;
+pub mod concurrent;
+pub mod entry;
+pub mod expiry;
+mod impls;
+pub mod iter;
+pub mod loading;
+pub mod meter;
+pub mod policy;
+#[cfg(feature = "serde")]
+mod serialization;
+mod stats;
+
+pub use crate::budget::{BudgetedCache, MemoryBudget};
+pub use crate::concurrent::ConcurrentMemoryLruCache;
+pub use crate::expiry::{Clock, SystemClock};
+pub use crate::loading::LoadingMemoryLruCache;
+pub use crate::meter::{KeyValueSize, Meter, SharedAllocation, SharedArcSize, ValueSize};
+pub use crate::policy::EvictionPolicy;
+#[cfg(feature = "stats")]
+pub use crate::stats::CacheStats;
+pub use lru::DefaultHasher;
+#[cfg(feature = "derive")]
+pub use memory_lru_derive::ResidentSize;
+
+use crate::entry::{Entry, OccupiedEntry, VacantEntry, ValueMut};
+use crate::iter::{Drain, IntoIter, Iter, IterMut, Keys, Values};
+use crate::stats::Recorder;
+
 use lru::LruCache;
 
-use std::hash::Hash;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
 use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const INITIAL_CAPACITY: Option<NonZeroUsize> = NonZeroUsize::new(4);
 
 /// An indicator of the resident in memory of a value.
+///
+/// Implementations for standard library types count the heap memory reachable
+/// from the value, excluding its inline size, and count the elements held by
+/// collections rather than their capacity.
 pub trait ResidentSize {
     /// Return the resident size of the value. Users of the trait will depend
     /// on this value to remain stable unless the value is mutated.
     fn resident_size(&self) -> usize;
 }
 
+/// The reason an entry left the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// The entry was evicted to bring the cache back within its memory budget.
+    Capacity,
+    /// The entry's value was replaced by an insertion under the same key.
+    Replaced,
+    /// The entry was explicitly removed from the cache.
+    Removed,
+    /// The entry outlived its time-to-live.
+    Expired,
+    /// The entry was too large to ever fit in the cache, or replaced a pinned
+    /// item which it would have taken over the budget, and was turned away on
+    /// insertion.
+    Rejected,
+}
+
+/// How `insert` and vacant entries treat an item larger than the whole memory
+/// budget.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Admit the item and evict until the budget is met, which flushes every
+    /// other item before the new one is evicted too. This is the default.
+    #[default]
+    Admit,
+    /// Turn the item away, leaving the cache untouched. Any item already
+    /// under the same key stays cached.
+    Reject,
+    /// Turn the item away and remove any item already under the same key, so
+    /// that the cache never serves a stale value for it.
+    Bypass,
+}
+
+/// The items displaced by an insertion, returned by `insert_returning`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Displaced<K, V> {
+    /// The value previously held under the inserted key, if any.
+    pub replaced: Option<V>,
+    /// The items evicted to bring the cache back within its memory budget,
+    /// from least to most recently used. This includes the inserted item
+    /// itself if it did not fit.
+    pub evicted: Vec<(K, V)>,
+    /// The inserted item, if it was turned away by the oversize policy or for
+    /// taking a pinned item over the budget.
+    pub rejected: Option<(K, V)>,
+}
+
+/// Whether an item mutated in place by `with_mut_returning` may be evicted to
+/// bring the cache back within its memory budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// The item is evicted in its turn, like any other.
+    Evictable,
+    /// Every other unpinned item is evicted first, and the item is kept even
+    /// if the cache remains over budget.
+    Protected,
+}
+
+/// What became of an item mutated in place, returned by `with_mut_returning`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mutated<K, V, U> {
+    /// The result of the closure.
+    pub result: U,
+    /// Whether the item is still in the cache. This is `false` if there was
+    /// no item under the key.
+    pub retained: bool,
+    /// The items evicted to bring the cache back within its memory budget,
+    /// in eviction order. This includes the mutated item itself if it was
+    /// not retained.
+    pub evicted: Vec<(K, V)>,
+}
+
+/// The error returned by `try_insert` for an item which can never fit in the
+/// cache, or which would replace a pinned item and take pinned items over the
+/// budget, handing the item back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OversizedError<K, V> {
+    /// The key of the rejected item.
+    pub key: K,
+    /// The value of the rejected item.
+    pub value: V,
+    /// The size the item would have been charged.
+    pub size: usize,
+    /// The size of the other pinned items, if the item would have replaced a
+    /// pinned one and so inherited its pin. Zero otherwise.
+    pub pinned_size: usize,
+    /// The maximum size of the cache at the time of insertion.
+    pub max_size: usize,
+}
+
+impl<K, V> fmt::Display for OversizedError<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.size <= self.max_size {
+            return write!(
+                f,
+                "pinned item of {} bytes would take pinned items to {} bytes, over the cache budget of {} bytes",
+                self.size,
+                self.pinned_size + self.size,
+                self.max_size
+            );
+        }
+
+        write!(
+            f,
+            "item of {} bytes exceeds the cache budget of {} bytes",
+            self.size, self.max_size
+        )
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> Error for OversizedError<K, V> {}
+
+/// The error returned by `pin` when pinned items alone would no longer fit in
+/// the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinError {
+    /// The size the item would have added to pinned items, including any
+    /// shared allocation not already pinned.
+    pub size: usize,
+    /// The total size of pinned items had the item been pinned.
+    pub pinned_size: usize,
+    /// The maximum size of the cache.
+    pub max_size: usize,
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pinning an item of {} bytes would take pinned items to {} bytes, over the cache budget of {} bytes",
+            self.size, self.pinned_size, self.max_size
+        )
+    }
+}
+
+impl Error for PinError {}
+
+/// A listener notified of every entry dropped by the cache.
+pub trait EvictionListener<K, V> {
+    /// Called with the key and value of an entry which has left the cache.
+    fn on_evict(&mut self, key: K, value: V, reason: EvictionReason);
+}
+
+impl<K, V, F: FnMut(K, V, EvictionReason)> EvictionListener<K, V> for F {
+    fn on_evict(&mut self, key: K, value: V, reason: EvictionReason) {
+        self(key, value, reason)
+    }
+}
+
+// A cached value along with the size it was charged when last measured.
+struct Slot<V> {
+    value: V,
+    size: usize,
+    // the id of the shared allocation the value refers to, charged separately.
+    share: Option<usize>,
+    expires: Option<Instant>,
+    pinned: bool,
+    // exempt from eviction while the budget is enforced on its release.
+    spared: bool,
+    // when the item was last inserted or looked up, by the ticks of the
+    // shared budget the cache is registered with, if any.
+    touched: u64,
+}
+
+// charge an item its newly measured size and shared allocation. a pinned
+// item which grows past what the budget leaves for pinned items is unpinned.
+fn recharge<V>(
+    slot: &mut Slot<V>,
+    new_size: usize,
+    new_share: Option<SharedAllocation>,
+    cur_size: &mut usize,
+    pinned_size: &mut usize,
+    shares: &mut Shares,
+    max_size: usize,
+) {
+    // the pin is lifted while the item is recharged, then taken up again if
+    // it still fits.
+    let mut pinned = 0;
+    if slot.pinned {
+        pinned = slot.size + slot.share.map_or(0, |id| shares.unpin(id));
+        *pinned_size -= pinned;
+    }
+
+    *cur_size -= slot.size;
+    *cur_size += new_size;
+    slot.size = new_size;
+
+    match new_share {
+        Some(share) if slot.share == Some(share.id) => {
+            let old_size = shares.resize(share);
+            *cur_size -= old_size;
+            *cur_size += share.size;
+            if shares.pinned(share.id) {
+                *pinned_size -= old_size;
+                *pinned_size += share.size;
+            }
+        }
+        _ => {
+            *cur_size += new_share.map_or(0, |share| shares.acquire(share));
+            *cur_size -= slot.share.map_or(0, |id| shares.release(id));
+            slot.share = new_share.map(|share| share.id);
+        }
+    }
+
+    if slot.pinned {
+        let new_pinned = new_size + slot.share.map_or(0, |id| shares.unpinned(id));
+        if new_pinned > pinned && *pinned_size + new_pinned > max_size {
+            slot.pinned = false;
+        } else {
+            *pinned_size += new_size + slot.share.map_or(0, |id| shares.pin(id));
+        }
+    }
+}
+
+// the shared allocations referred to by items, each charged once.
+#[derive(Default)]
+struct Shares {
+    allocations: HashMap<usize, Allocation>,
+}
+
+struct Allocation {
+    // how many items refer to the allocation, and how many of those are
+    // pinned.
+    refs: usize,
+    pins: usize,
+    size: usize,
+}
+
+impl Shares {
+    // returns the bytes newly charged.
+    fn acquire(&mut self, share: SharedAllocation) -> usize {
+        let allocation = self.allocations.entry(share.id).or_insert(Allocation {
+            refs: 0,
+            pins: 0,
+            size: share.size,
+        });
+        allocation.refs += 1;
+        if allocation.refs == 1 {
+            allocation.size
+        } else {
+            0
+        }
+    }
+
+    // returns the bytes no longer charged. a pinned item must be unpinned
+    // first.
+    fn release(&mut self, id: usize) -> usize {
+        let allocation = match self.allocations.get_mut(&id) {
+            Some(allocation) => allocation,
+            None => return 0,
+        };
+
+        allocation.refs -= 1;
+        if allocation.refs > 0 {
+            return 0;
+        }
+
+        let size = allocation.size;
+        self.allocations.remove(&id);
+        size
+    }
+
+    // returns the bytes newly pinned.
+    fn pin(&mut self, id: usize) -> usize {
+        match self.allocations.get_mut(&id) {
+            Some(allocation) => {
+                allocation.pins += 1;
+                if allocation.pins == 1 {
+                    allocation.size
+                } else {
+                    0
+                }
+            }
+            None => 0,
+        }
+    }
+
+    // returns the bytes no longer pinned.
+    fn unpin(&mut self, id: usize) -> usize {
+        match self.allocations.get_mut(&id) {
+            Some(allocation) => {
+                allocation.pins -= 1;
+                if allocation.pins == 0 {
+                    allocation.size
+                } else {
+                    0
+                }
+            }
+            None => 0,
+        }
+    }
+
+    fn pinned(&self, id: usize) -> bool {
+        matches!(self.allocations.get(&id), Some(allocation) if allocation.pins > 0)
+    }
+
+    // the bytes pinning an item referring to the allocation would pin.
+    fn unpinned(&self, id: usize) -> usize {
+        match self.allocations.get(&id) {
+            Some(allocation) if allocation.pins == 0 => allocation.size,
+            _ => 0,
+        }
+    }
+
+    // the size of the allocation if only one pinned item refers to it.
+    fn sole_pin(&self, id: usize) -> usize {
+        match self.allocations.get(&id) {
+            Some(allocation) if allocation.pins == 1 => allocation.size,
+            _ => 0,
+        }
+    }
+
+    // the bytes acquiring the allocation would charge.
+    fn pending(&self, share: SharedAllocation) -> usize {
+        if self.allocations.contains_key(&share.id) {
+            0
+        } else {
+            share.size
+        }
+    }
+
+    // record the allocation's new size, returning the size it was charged.
+    fn resize(&mut self, share: SharedAllocation) -> usize {
+        match self.allocations.get_mut(&share.id) {
+            Some(allocation) => mem::replace(&mut allocation.size, share.size),
+            None => share.size,
+        }
+    }
+
+    // the size of the allocation if only one item refers to it.
+    fn exclusive(&self, id: usize) -> usize {
+        match self.allocations.get(&id) {
+            Some(allocation) if allocation.refs == 1 => allocation.size,
+            _ => 0,
+        }
+    }
+}
+
 /// An LRU-cache which operates on memory used.
-pub struct MemoryLruCache<K, V> {
-    inner: LruCache<K, V>,
+///
+/// The memory charged for each entry is determined by the `Meter`, which
+/// by default only counts the resident size of values.
+///
+/// Items may be given a time-to-live, after which lookups treat them as
+/// absent. Expired items keep counting toward `len` and `current_size`, and
+/// are still visited by iterators, until they are looked up through `get`
+/// or `with_mut` or reclaimed by `purge_expired`.
+///
+/// Which item is evicted when the cache is over budget is decided by its
+/// eviction policy: by default the least recently used, or one of those in
+/// the `policy` module.
+///
+/// Items may also be pinned, exempting them from eviction to make room for
+/// others. Pinned items still count toward `current_size`, still expire, and
+/// can still be removed explicitly.
+pub struct MemoryLruCache<K, V, M = ValueSize, S = DefaultHasher> {
+    inner: LruCache<K, Slot<V>, S>,
+    meter: M,
     cur_size: usize,
     max_size: usize,
+    pinned_size: usize,
+    listener: Option<Box<dyn EvictionListener<K, V> + Send>>,
+    stats: Recorder,
+    clock: Box<dyn Clock + Send>,
+    default_ttl: Option<Duration>,
+    // whether any item was ever given a deadline; lets lookups skip the
+    // expiry check entirely for caches which never use one.
+    expiring: bool,
+    oversize: OversizePolicy,
+    // `None` for plain LRU, which needs no bookkeeping beyond `inner`.
+    policy: Option<Box<dyn EvictionPolicy<K> + Send>>,
+    // the access counter of the shared budget the cache is registered with.
+    ticks: Option<Arc<AtomicU64>>,
+    shares: Shares,
+    // set by the first pin, which requires `K: Clone`; lets eviction pick an
+    // item past pinned ones without disturbing their order.
+    clone_key: Option<fn(&K) -> K>,
 }
 
 impl<K: Eq + Hash, V: ResidentSize> MemoryLruCache<K, V> {
     /// Create a new cache with a maximum cumulative size of values.
     pub fn new(max_size: usize) -> Self {
+        MemoryLruCache::with_meter(max_size, ValueSize)
+    }
+}
+
+impl<K: Eq + Hash, V: ResidentSize, S: BuildHasher> MemoryLruCache<K, V, ValueSize, S> {
+    /// Create a new cache with a maximum cumulative size of values, hashing
+    /// keys with the given hasher.
+    pub fn with_hasher(max_size: usize, hash_builder: S) -> Self {
+        MemoryLruCache::with_meter_and_hasher(max_size, ValueSize, hash_builder)
+    }
+}
+
+impl<K: Eq + Hash, V, M: Meter<K, V>> MemoryLruCache<K, V, M> {
+    /// Create a new cache with a maximum cumulative size of entries, as
+    /// measured by the given meter.
+    pub fn with_meter(max_size: usize, meter: M) -> Self {
+        MemoryLruCache::with_meter_and_hasher(max_size, meter, DefaultHasher::default())
+    }
+}
+
+impl<K: Eq + Hash, V, F: Fn(&K, &V) -> usize> MemoryLruCache<K, V, F> {
+    /// Create a new cache with a maximum cumulative size of entries, as
+    /// returned by the given weigher. Unlike `new`, values need not implement
+    /// `ResidentSize`.
+    pub fn with_weigher(max_size: usize, weigher: F) -> Self {
+        MemoryLruCache::with_meter(max_size, weigher)
+    }
+}
+
+impl<K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> MemoryLruCache<K, V, M, S> {
+    /// Create a new cache with a maximum cumulative size of entries, as
+    /// measured by the given meter, hashing keys with the given hasher.
+    pub fn with_meter_and_hasher(max_size: usize, meter: M, hash_builder: S) -> Self {
         MemoryLruCache {
-            inner: LruCache::new(INITIAL_CAPACITY.expect("4 != 0; qed")),
-            max_size: max_size,
+            inner: LruCache::with_hasher(INITIAL_CAPACITY.expect("4 != 0; qed"), hash_builder),
+            meter,
+            max_size,
             cur_size: 0,
+            pinned_size: 0,
+            listener: None,
+            stats: Recorder::default(),
+            clock: Box::new(SystemClock),
+            default_ttl: None,
+            expiring: false,
+            oversize: OversizePolicy::default(),
+            policy: None,
+            ticks: None,
+            shares: Shares::default(),
+            clone_key: None,
+        }
+    }
+
+    /// Set the listener notified of every entry the cache drops, replacing
+    /// any previously set listener.
+    pub fn set_eviction_listener(
+        &mut self,
+        listener: impl EvictionListener<K, V> + Send + 'static,
+    ) {
+        self.listener = Some(Box::new(listener));
+    }
+
+    /// Set the clock used to decide when items expire. Deadlines of items
+    /// already in the cache are not adjusted.
+    pub fn set_clock(&mut self, clock: impl Clock + Send + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Set the time-to-live given to items inserted through `insert`, or
+    /// `None` for items to live until evicted. Items already in the cache are
+    /// not affected.
+    pub fn set_default_ttl(&mut self, ttl: Option<Duration>) {
+        self.default_ttl = ttl;
+    }
+
+    /// Set the policy choosing which item to evict when the cache is over
+    /// budget, replacing the default of evicting the least recently used.
+    /// Items already in the cache are handed to the policy from least to most
+    /// recently used, as though inserted in that order.
+    pub fn set_eviction_policy(&mut self, policy: impl EvictionPolicy<K> + Send + 'static) {
+        let mut policy = Box::new(policy);
+        policy.set_capacity(self.max_size);
+        for (k, slot) in self.inner.iter().rev() {
+            policy.on_insert(k, slot.size);
         }
+
+        self.policy = Some(policy);
+        self.readjust_down();
     }
 
-    /// Insert an item.
+    /// Set how `insert` and vacant entries treat items larger than the whole
+    /// memory budget.
+    pub fn set_oversize_policy(&mut self, policy: OversizePolicy) {
+        self.oversize = policy;
+    }
+
+    /// Insert an item, expiring after the default time-to-live if one is set.
+    /// Items larger than the whole memory budget are handled according to the
+    /// oversize policy.
     pub fn insert(&mut self, key: K, val: V) {
+        let expires = self.default_deadline();
+        self.insert_slot(key, val, expires);
+    }
+
+    /// Insert an item, failing if it is larger than the whole memory budget.
+    /// On failure the item is handed back and the cache is left untouched.
+    pub fn try_insert(&mut self, key: K, val: V) -> Result<(), OversizedError<K, V>> {
+        let (key, val, size) = self.fit(key, val)?;
+        let expires = self.default_deadline();
+        self.insert_into(key, val, size, expires, |cache, k, v, reason| {
+            cache.notify(k, v, reason)
+        });
+        Ok(())
+    }
+
+    /// Insert an item like `insert`, but return the items it displaced
+    /// instead of passing them to the eviction listener.
+    pub fn insert_returning(&mut self, key: K, val: V) -> Displaced<K, V> {
+        let mut displaced = Displaced {
+            replaced: None,
+            evicted: Vec::new(),
+            rejected: None,
+        };
+
+        let size = self.meter.measure(&key, &val);
+        let expires = self.default_deadline();
+        self.insert_into(key, val, size, expires, |_, k, v, reason| match reason {
+            EvictionReason::Replaced => displaced.replaced = Some(v),
+            EvictionReason::Rejected => displaced.rejected = Some((k, v)),
+            _ => displaced.evicted.push((k, v)),
+        });
+
+        displaced
+    }
+
+    /// Insert an item which expires after the given time-to-live.
+    pub fn insert_with_ttl(&mut self, key: K, val: V, ttl: Duration) {
+        let expires = self.deadline(ttl);
+        self.insert_slot(key, val, expires);
+    }
+
+    /// Remove all expired items from the cache, reclaiming their memory.
+    /// The eviction listener, if any, is notified of each of them.
+    pub fn purge_expired(&mut self) {
+        if !self.expiring {
+            return;
+        }
+
+        let now = self.clock.now();
+        self.sweep(
+            |_, slot| match slot.expires {
+                Some(at) => at > now,
+                None => true,
+            },
+            EvictionReason::Expired,
+            false,
+        );
+    }
+
+    // measure an item, handing it back if it is larger than the whole budget.
+    // a replaced pinned item passes its pin on, so the item must also fit
+    // alongside the other pinned items.
+    fn fit(&self, key: K, val: V) -> Result<(K, V, usize), OversizedError<K, V>> {
+        let size = self.meter.measure(&key, &val);
+        let charge = size + self.unshared(&key, &val);
+        let pinned_size = self.pin_overflow(&key, &val, size);
+        if charge > self.max_size || pinned_size.is_some() {
+            return Err(OversizedError {
+                key,
+                value: val,
+                size: charge,
+                pinned_size: pinned_size.unwrap_or(0),
+                max_size: self.max_size,
+            });
+        }
+
+        Ok((key, val, size))
+    }
+
+    // the size of the other pinned items, if the item under the key is pinned
+    // and replacing it with the value would take pinned items over budget.
+    fn pin_overflow(&self, key: &K, val: &V, size: usize) -> Option<usize> {
+        let old = match self.inner.peek(key) {
+            Some(slot) if slot.pinned => slot,
+            _ => return None,
+        };
+
+        // a shared allocation only the old item pins stays pinned only if the
+        // new value refers to it too.
+        let released = old.share.map_or(0, |id| self.shares.sole_pin(id));
+        let acquired = match self.meter.shared(key, val) {
+            Some(share) if old.share == Some(share.id) && released > 0 => share.size,
+            Some(share) if self.shares.pinned(share.id) => 0,
+            Some(share) => share.size,
+            None => 0,
+        };
+
+        let others = self.pinned_size - old.size - released;
+        if others + size + acquired > self.max_size {
+            Some(others)
+        } else {
+            None
+        }
+    }
+
+    // the bytes of the item's shared allocation not yet charged to the cache.
+    fn unshared(&self, key: &K, val: &V) -> usize {
+        self.meter
+            .shared(key, val)
+            .map_or(0, |share| self.shares.pending(share))
+    }
+
+    fn insert_slot(&mut self, key: K, val: V, expires: Option<Instant>) {
+        let size = self.meter.measure(&key, &val);
+        self.insert_into(key, val, size, expires, |cache, k, v, reason| {
+            cache.notify(k, v, reason)
+        });
+    }
+
+    // insert an item, handing every item displaced along the way to the sink
+    // rather than the eviction listener.
+    fn insert_into(
+        &mut self,
+        key: K,
+        val: V,
+        size: usize,
+        expires: Option<Instant>,
+        mut sink: impl FnMut(&mut Self, K, V, EvictionReason),
+    ) {
+        if size + self.unshared(&key, &val) > self.max_size {
+            match self.oversize {
+                OversizePolicy::Admit => {}
+                OversizePolicy::Reject => {
+                    sink(self, key, val, EvictionReason::Rejected);
+                    return;
+                }
+                OversizePolicy::Bypass => {
+                    if let Some((k, slot)) = self.inner.pop_entry(&key) {
+                        self.discharge(&k, &slot);
+                        sink(self, k, slot.value, EvictionReason::Replaced);
+                    }
+                    sink(self, key, val, EvictionReason::Rejected);
+                    return;
+                }
+            }
+        }
+
+        // the pin carries over to the new value, so it must fit alongside the
+        // other pinned items.
+        if self.pin_overflow(&key, &val, size).is_some() {
+            sink(self, key, val, EvictionReason::Rejected);
+            return;
+        }
+
+        if let Some((k, v)) = self.push_slot(key, val, size, expires) {
+            sink(self, k, v, EvictionReason::Replaced);
+        }
+
+        while let Some((k, v)) = self.pop_over_budget() {
+            sink(self, k, v, EvictionReason::Capacity);
+        }
+        self.stats.size(self.cur_size);
+    }
+
+    // insert an item of the given size as the most recently used without
+    // enforcing the budget, returning any item previously under the key.
+    // a pin on the key carries over to the new item.
+    fn push_slot(
+        &mut self,
+        key: K,
+        val: V,
+        size: usize,
+        expires: Option<Instant>,
+    ) -> Option<(K, V)> {
         let cap = self.inner.cap().get();
 
         // grow the cache as necessary; it operates on amount of items
@@ -64,44 +741,325 @@ impl<K: Eq + Hash, V: ResidentSize> MemoryLruCache<K, V> {
             self.inner.resize(next_cap);
         }
 
-        self.cur_size += val.resident_size();
+        let share = self.meter.shared(&key, &val);
+        self.cur_size += size + share.map_or(0, |share| self.shares.acquire(share));
+        self.expiring |= expires.is_some();
+
+        let slot = Slot {
+            value: val,
+            size,
+            share: share.map(|share| share.id),
+            expires,
+            pinned: false,
+            spared: false,
+            touched: self.tick(),
+        };
+
+        // account for any element displaced from the cache. the capacity was
+        // grown above, so `push` only returns the entry previously under `key`.
+        let displaced = self.inner.push(key, slot);
+        self.stats.insertion(displaced.is_some());
 
-        // account for any element displaced from the cache.
-        if let Some(lru) = self.inner.put(key, val) {
-            self.cur_size -= lru.resident_size();
+        let (key, slot) = self
+            .inner
+            .iter_mut()
+            .next()
+            .expect("item was just inserted as most recently used; qed");
+        if let Some(policy) = self.policy.as_mut() {
+            match displaced {
+                Some(_) => {
+                    policy.on_resize(key, size);
+                    policy.on_access(key);
+                }
+                None => policy.on_insert(key, size),
+            }
         }
 
-        self.readjust_down();
+        let (key, old) = displaced?;
+        if old.pinned {
+            let shares = &mut self.shares;
+            self.pinned_size -= old.size + old.share.map_or(0, |id| shares.unpin(id));
+            slot.pinned = true;
+            self.pinned_size += size + slot.share.map_or(0, |id| shares.pin(id));
+        }
+        let shared = match old.share {
+            Some(id) => self.shares.release(id),
+            None => 0,
+        };
+        self.cur_size -= old.size + shared;
+        Some((key, old.value))
+    }
+
+    /// Get the entry for the given key for in-place manipulation. An existing
+    /// item becomes the most recently used.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, M, S> {
+        self.expire(&key);
+        let now = self.tick();
+        let occupied = match self.inner.get_mut(&key) {
+            Some(slot) => {
+                slot.touched = now;
+                true
+            }
+            None => false,
+        };
+        self.stats.lookup(occupied);
+
+        if occupied {
+            if let Some(policy) = self.policy.as_mut() {
+                policy.on_access(&key);
+            }
+
+            Entry::Occupied(OccupiedEntry {
+                key,
+                value: ValueMut::new(self),
+            })
+        } else {
+            Entry::Vacant(VacantEntry { cache: self, key })
+        }
+    }
+
+    /// Get a mutable reference to the item under the key, inserting the
+    /// result of the fallible closure if there is none. Any error is
+    /// returned and nothing is inserted.
+    pub fn get_or_try_insert_with<E>(
+        &mut self,
+        key: K,
+        with: impl FnOnce() -> Result<V, E>,
+    ) -> Result<ValueMut<'_, K, V, M, S>, E> {
+        self.entry(key).or_try_insert_with(with)
     }
 
     /// Get a reference to an item in the cache. It is a logic error for its
     /// heap size to be altered while borrowed.
-    pub fn get(&mut self, key: &K) -> Option<&V> {
-        self.inner.get(key)
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.expire(key);
+        let now = self.tick();
+        let entry = self.inner.get_key_value_mut(key);
+        self.stats.lookup(entry.is_some());
+
+        let (key, slot) = entry?;
+        slot.touched = now;
+        if let Some(policy) = self.policy.as_mut() {
+            policy.on_access(key);
+        }
+        Some(&slot.value)
     }
 
-    /// Execute a closure with the value under the provided key.
-    pub fn with_mut<U>(&mut self, key: &K, with: impl FnOnce(Option<&mut V>) -> U) -> U {
-        let mut val = self.inner.get_mut(key);
-        let prev_size = val.as_ref().map_or(0, |v| v.resident_size());
+    /// Get a guard giving mutable access to an item in the cache, which
+    /// becomes the most recently used. Its size is recomputed when the guard
+    /// is dropped, and other items evicted if the cache is over budget. The
+    /// item itself is kept even then, unless `ValueMut::allow_eviction` was
+    /// called.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<ValueMut<'_, K, V, M, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        if self.touch(key) {
+            Some(ValueMut::sparing(self))
+        } else {
+            None
+        }
+    }
 
-        let res = with(val.as_mut().map(|v: &mut &mut V| &mut **v));
+    /// Execute a closure with the value under the provided key. If the cache
+    /// is over budget afterwards, the item may be evicted along with others;
+    /// use `with_mut_returning` to find out or to prevent it.
+    pub fn with_mut<Q, U>(&mut self, key: &Q, with: impl FnOnce(Option<&mut V>) -> U) -> U
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        if !self.touch(key) {
+            return with(None);
+        }
 
-        let new_size = val.as_ref().map_or(0, |v| v.resident_size());
+        let res = with(Some(self.mru_value()));
+        self.release_mru(false);
+        res
+    }
 
-        self.cur_size -= prev_size;
-        self.cur_size += new_size;
+    /// Execute a closure with the value under the provided key like
+    /// `with_mut`, but return the items evicted afterwards instead of passing
+    /// them to the eviction listener, and whether the item itself survived.
+    pub fn with_mut_returning<Q, U>(
+        &mut self,
+        key: &Q,
+        retention: Retention,
+        with: impl FnOnce(Option<&mut V>) -> U,
+    ) -> Mutated<K, V, U>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        if !self.touch(key) {
+            return Mutated {
+                result: with(None),
+                retained: false,
+                evicted: Vec::new(),
+            };
+        }
 
-        self.readjust_down();
+        let result = with(Some(self.mru_value()));
+        let mut evicted = Vec::new();
+        self.release_mru_into(retention == Retention::Protected, |_, k, v| {
+            evicted.push((k, v))
+        });
 
-        res
+        Mutated {
+            result,
+            retained: self.inner.contains(key),
+            evicted,
+        }
     }
 
-    /// Currently-used size of values in bytes.
+    /// Currently-used size of entries in bytes, as measured by the meter.
     pub fn current_size(&self) -> usize {
         self.cur_size
     }
 
+    /// Size of pinned entries in bytes, as measured by the meter, including
+    /// the shared allocations they refer to. This is included in
+    /// `current_size`.
+    pub fn pinned_size(&self) -> usize {
+        self.pinned_size
+    }
+
+    /// Pin an item, exempting it from eviction when the cache is over budget,
+    /// and return whether it was present. Fails, leaving the item unpinned, if
+    /// pinned items would no longer fit in the budget.
+    ///
+    /// The pin stays when the item is replaced, unless the new value would
+    /// take pinned items over the budget; such a value is turned away like an
+    /// oversized one. A pinned item growing past the budget in place loses
+    /// its pin instead, as do pinned items no longer fitting a shrunk budget.
+    pub fn pin<Q>(&mut self, key: &Q) -> Result<bool, PinError>
+    where
+        K: Borrow<Q> + Clone,
+        Q: ?Sized + Hash + Eq,
+    {
+        let shares = &mut self.shares;
+        let slot = match self.inner.peek_mut(key) {
+            Some(slot) => slot,
+            None => return Ok(false),
+        };
+
+        if !slot.pinned {
+            let size = slot.size + slot.share.map_or(0, |id| shares.unpinned(id));
+            let pinned_size = self.pinned_size + size;
+            if pinned_size > self.max_size {
+                return Err(PinError {
+                    size,
+                    pinned_size,
+                    max_size: self.max_size,
+                });
+            }
+
+            slot.pinned = true;
+            if let Some(id) = slot.share {
+                shares.pin(id);
+            }
+            self.pinned_size = pinned_size;
+            self.clone_key = Some(K::clone);
+        }
+
+        Ok(true)
+    }
+
+    /// Returns a bool indicating whether the item under the key is pinned.
+    pub fn is_pinned<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        matches!(self.inner.peek(key), Some(slot) if slot.pinned)
+    }
+
+    /// Unpin an item and return whether it was present. The item becomes
+    /// eligible for eviction again, and is evicted straight away if the cache
+    /// is over budget and it is the least recently used.
+    pub fn unpin<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let shares = &mut self.shares;
+        let slot = match self.inner.peek_mut(key) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        if slot.pinned {
+            slot.pinned = false;
+            self.pinned_size -= slot.size + slot.share.map_or(0, |id| shares.unpin(id));
+            self.readjust_down();
+        }
+
+        true
+    }
+
+    /// Returns a snapshot of the statistics gathered so far.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    /// Reset all gathered statistics to zero.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&mut self) {
+        self.stats.reset();
+    }
+
+    /// Maximum cumulative size of entries in bytes.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Change the maximum cumulative size of entries. Shrinking the budget
+    /// evicts least recently used items until the cache fits again, passing
+    /// them to the eviction listener. Should pinned items no longer fit, the
+    /// least recently used lose their pins until the rest do.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.resize(max_size);
+        self.readjust_down();
+    }
+
+    /// Change the maximum cumulative size of entries like `set_max_size`, but
+    /// return the evicted items in eviction order instead of passing them to
+    /// the eviction listener.
+    #[must_use]
+    pub fn set_max_size_returning(&mut self, max_size: usize) -> Vec<(K, V)> {
+        self.resize(max_size);
+
+        let mut evicted = Vec::new();
+        while let Some(entry) = self.pop_over_budget() {
+            evicted.push(entry);
+        }
+        evicted
+    }
+
+    fn resize(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        if let Some(policy) = self.policy.as_mut() {
+            policy.set_capacity(max_size);
+        }
+
+        for (_, slot) in self.inner.iter_mut().rev() {
+            if self.pinned_size <= max_size {
+                break;
+            }
+            if slot.pinned {
+                slot.pinned = false;
+                let shares = &mut self.shares;
+                self.pinned_size -= slot.size + slot.share.map_or(0, |id| shares.unpin(id));
+            }
+        }
+    }
+
     /// Returns the number of key-value pairs that are currently in the cache.
     pub fn len(&self) -> usize {
         self.inner.len()
@@ -109,8 +1067,12 @@ impl<K: Eq + Hash, V: ResidentSize> MemoryLruCache<K, V> {
 
     /// Returns a bool indicating whether the given key is in the cache.
     /// Does not update the LRU list.
-    pub fn contains(&self, key: &K) -> bool {
-        self.inner.contains(key)
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.peek(key).is_some()
     }
 
     /// Returns a bool indicating whether the cache is empty or not.
@@ -121,30 +1083,484 @@ impl<K: Eq + Hash, V: ResidentSize> MemoryLruCache<K, V> {
     /// Returns a reference to the value corresponding to the key in the cache or
     /// None if it is not present in the cache. Unlike get, peek does not update the
     /// LRU list so the key's position will be unchanged.
-    pub fn peek(&self, key: &K) -> Option<&V> {
-        self.inner.peek(key)
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.inner
+            .peek(key)
+            .filter(|slot| !self.is_expired(slot))
+            .map(|slot| &slot.value)
+    }
+
+    /// Returns the least recently used key-value pair without updating the
+    /// LRU list. Expired items are skipped.
+    pub fn peek_lru(&self) -> Option<(&K, &V)> {
+        self.inner
+            .iter()
+            .rev()
+            .find(|(_, slot)| !self.is_expired(slot))
+            .map(|(k, slot)| (k, &slot.value))
+    }
+
+    /// Remove an item from the cache, returning its value if it was present.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (k, slot) = self.inner.pop_entry(key)?;
+        self.discharge(&k, &slot);
+        Some(slot.value)
+    }
+
+    /// Remove and return the least recently used key-value pair.
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        let (k, slot) = self.inner.pop_lru()?;
+        self.discharge(&k, &slot);
+        Some((k, slot.value))
+    }
+
+    /// Remove all items from the cache. The eviction listener, if any, is
+    /// notified of each of them.
+    pub fn clear(&mut self) {
+        if self.listener.is_none() {
+            self.inner.clear();
+            self.cur_size = 0;
+            self.pinned_size = 0;
+            self.shares = Shares::default();
+            if let Some(policy) = self.policy.as_mut() {
+                policy.clear();
+            }
+            return;
+        }
+
+        while let Some((k, v)) = self.pop_lru() {
+            self.notify(k, v, EvictionReason::Removed);
+        }
+    }
+
+    /// Remove all items from the cache, returning them in order from least
+    /// to most recently used. Items not consumed by the time the iterator is
+    /// dropped are removed as if by `clear`.
+    pub fn drain(&mut self) -> Drain<'_, K, V, M, S> {
+        Drain { cache: self }
+    }
+
+    /// An iterator over the items of the cache, from most to least recently
+    /// used. Does not update the LRU list.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: self.inner.iter(),
+        }
+    }
+
+    /// A guard for iterating over the items of the cache with mutable
+    /// access to values, from most to least recently used. The size of every
+    /// item is recomputed when the guard is dropped, evicting items if the
+    /// cache has outgrown its memory budget.
+    ///
+    /// ```
+    /// # use memory_lru::MemoryLruCache;
+    /// let mut cache = MemoryLruCache::new(1024);
+    /// cache.insert("a", vec![1u8, 2, 3]);
+    ///
+    /// for (_, v) in &mut cache.iter_mut() {
+    ///     v.push(4);
+    /// }
+    ///
+    /// assert_eq!(cache.current_size(), 4);
+    /// ```
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V, M, S> {
+        IterMut { cache: self }
+    }
+
+    /// An iterator over the keys of the cache, from most to least recently
+    /// used.
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    /// An iterator over the values of the cache, from most to least recently
+    /// used.
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Retain only the items for which the predicate returns `true`, keeping
+    /// their relative order in the LRU list. Removed items are passed to the
+    /// eviction listener, if any.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &mut V) -> bool) {
+        self.sweep(
+            |k, slot| keep(k, &mut slot.value),
+            EvictionReason::Removed,
+            true,
+        );
+        self.readjust_down();
+    }
+
+    // remove every item rejected by the predicate, optionally re-measuring
+    // the survivors, while keeping their relative order in the LRU list.
+    fn sweep(
+        &mut self,
+        mut keep: impl FnMut(&K, &mut Slot<V>) -> bool,
+        reason: EvictionReason,
+        remeasure: bool,
+    ) {
+        // the predicate sees every item before any is removed, so the cache
+        // stays consistent should it panic.
+        let mut verdicts = Vec::with_capacity(self.inner.len());
+        for (k, slot) in self.inner.iter_mut() {
+            let kept = keep(k, slot);
+            if kept && remeasure {
+                let new_size = self.meter.measure(k, &slot.value);
+                let new_share = self.meter.shared(k, &slot.value);
+                recharge(
+                    slot,
+                    new_size,
+                    new_share,
+                    &mut self.cur_size,
+                    &mut self.pinned_size,
+                    &mut self.shares,
+                    self.max_size,
+                );
+                if let Some(policy) = self.policy.as_mut() {
+                    policy.on_resize(k, new_size);
+                }
+            }
+            verdicts.push(kept);
+        }
+
+        if verdicts.iter().all(|&kept| kept) {
+            return;
+        }
+
+        // every entry is popped from the back and either dropped or pushed
+        // onto the front, so after a full rotation the survivors are back in
+        // their original order.
+        while let Some(kept) = verdicts.pop() {
+            let (k, slot) = match self.inner.pop_lru() {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            if kept {
+                self.inner.put(k, slot);
+            } else {
+                self.discharge(&k, &slot);
+                self.notify(k, slot.value, reason);
+            }
+        }
+    }
+
+    // remove the item under the key if it has expired.
+    fn expire<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        if !self.expiring {
+            return;
+        }
+
+        let expired = match self.inner.peek(key) {
+            Some(slot) => self.is_expired(slot),
+            None => false,
+        };
+
+        if expired {
+            if let Some((k, slot)) = self.inner.pop_entry(key) {
+                self.discharge(&k, &slot);
+                self.notify(k, slot.value, EvictionReason::Expired);
+            }
+        }
+    }
+
+    fn is_expired(&self, slot: &Slot<V>) -> bool {
+        match slot.expires {
+            Some(at) => at <= self.clock.now(),
+            None => false,
+        }
+    }
+
+    fn default_deadline(&self) -> Option<Instant> {
+        self.default_ttl.and_then(|ttl| self.deadline(ttl))
+    }
+
+    // the deadline for an item inserted now; `None` if it lies beyond what
+    // `Instant` can represent, which is as good as never.
+    fn deadline(&self, ttl: Duration) -> Option<Instant> {
+        self.clock.now().checked_add(ttl)
+    }
+
+    // look up an item for mutation, making it the most recently used, and
+    // return whether it was present.
+    fn touch<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.expire(key);
+        let now = self.tick();
+        let entry = self.inner.get_key_value_mut(key);
+        self.stats.lookup(entry.is_some());
+
+        let (key, slot) = match entry {
+            Some(entry) => entry,
+            None => return false,
+        };
+        slot.touched = now;
+        if let Some(policy) = self.policy.as_mut() {
+            policy.on_access(key);
+        }
+        true
+    }
+
+    // the value of the item just made the most recently used.
+    fn mru_value(&mut self) -> &mut V {
+        self.inner
+            .iter_mut()
+            .next()
+            .map(|(_, slot)| &mut slot.value)
+            .expect("the item was just made most recently used; qed")
+    }
+
+    fn release_mru(&mut self, spare: bool) {
+        self.release_mru_into(spare, |cache, k, v| {
+            cache.notify(k, v, EvictionReason::Capacity)
+        });
+    }
+
+    // release an item inserted through a vacant entry. one which has outgrown
+    // the whole budget is turned away on its own, rather than flushing the
+    // cache, unless the oversize policy admits it.
+    fn release_inserted(&mut self) {
+        let charge = self.remeasure_mru(false);
+        if charge > self.max_size && self.oversize != OversizePolicy::Admit {
+            // the item is the most recently used, so the first the sweep sees.
+            let mut first = true;
+            self.sweep(
+                |_, _| !mem::replace(&mut first, false),
+                EvictionReason::Rejected,
+                false,
+            );
+        } else {
+            self.readjust_down();
+        }
+        self.stats.size(self.cur_size);
+    }
+
+    // re-measure the most recently used item after it was handed out for
+    // mutation, then enforce the budget, evicting the item itself only as a
+    // last resort unless it is spared.
+    fn release_mru_into(&mut self, spare: bool, mut sink: impl FnMut(&mut Self, K, V)) {
+        self.remeasure_mru(spare);
+        while let Some((k, v)) = self.pop_over_budget() {
+            sink(self, k, v);
+        }
+        if spare {
+            self.unspare();
+        }
+        self.stats.size(self.cur_size);
+    }
+
+    // re-measure the most recently used item, marking whether it is spared
+    // from eviction, and return the bytes evicting it would free.
+    fn remeasure_mru(&mut self, spare: bool) -> usize {
+        let (k, slot) = match self.inner.iter_mut().next() {
+            Some(entry) => entry,
+            None => return 0,
+        };
+
+        let new_size = self.meter.measure(k, &slot.value);
+        let new_share = self.meter.shared(k, &slot.value);
+        recharge(
+            slot,
+            new_size,
+            new_share,
+            &mut self.cur_size,
+            &mut self.pinned_size,
+            &mut self.shares,
+            self.max_size,
+        );
+        if let Some(policy) = self.policy.as_mut() {
+            policy.on_resize(k, new_size);
+        }
+        slot.spared = spare;
+
+        match slot.share {
+            Some(id) => slot.size + self.shares.exclusive(id),
+            None => slot.size,
+        }
+    }
+
+    // clear the mark on the spared item. it is still the most recently used
+    // unless pinned items were moved ahead of it.
+    fn unspare(&mut self) {
+        let spared = self
+            .inner
+            .iter_mut()
+            .map(|(_, slot)| slot)
+            .find(|slot| slot.spared);
+
+        if let Some(slot) = spared {
+            slot.spared = false;
+        }
     }
 
     fn readjust_down(&mut self) {
         // remove elements until we are below the memory target.
-        while self.cur_size > self.max_size {
-            match self.inner.pop_lru() {
-                Some((_, v)) => self.cur_size -= v.resident_size(),
-                _ => break,
+        while let Some((k, v)) = self.pop_over_budget() {
+            self.notify(k, v, EvictionReason::Capacity);
+        }
+        self.stats.size(self.cur_size);
+    }
+
+    // evict an unpinned item chosen by the eviction policy if the cache is
+    // over budget and evicting could help.
+    fn pop_over_budget(&mut self) -> Option<(K, V)> {
+        if self.cur_size <= self.max_size {
+            return None;
+        }
+
+        self.pop_victim()
+    }
+
+    // evict the unpinned item chosen by the eviction policy, unless only
+    // pinned items take up space.
+    fn pop_victim(&mut self) -> Option<(K, V)> {
+        if self.cur_size == self.pinned_size {
+            return None;
+        }
+
+        let (k, slot) = match self.policy {
+            Some(_) => self.pop_policy_victim(),
+            None => self.pop_lru_unpinned(),
+        }?;
+
+        let freed = slot.size + slot.share.map_or(0, |id| self.shares.release(id));
+        self.cur_size -= freed;
+        self.stats.eviction(freed);
+        Some((k, slot.value))
+    }
+
+    fn pop_lru_unpinned(&mut self) -> Option<(K, Slot<V>)> {
+        // a spared item starts out at the front, so only pinned items are
+        // ever ahead of it and nothing is left to evict once it is reached.
+        let clone_key = match self.clone_key {
+            Some(clone_key) => clone_key,
+            None => {
+                return match self.inner.peek_lru() {
+                    Some((_, slot)) if slot.spared => None,
+                    _ => self.inner.pop_lru(),
+                }
             }
+        };
+
+        let key = match self.inner.iter().rev().find(|(_, slot)| !slot.pinned) {
+            Some((_, slot)) if slot.spared => return None,
+            Some((key, _)) => clone_key(key),
+            None => return None,
+        };
+        self.inner.pop_entry(&key)
+    }
+
+    fn pop_policy_victim(&mut self) -> Option<(K, Slot<V>)> {
+        let inner = &self.inner;
+        let evictable =
+            |key: &K| matches!(inner.peek(key), Some(slot) if !slot.pinned && !slot.spared);
+        let key = self.policy.as_mut()?.victim(&evictable)?;
+        self.inner.pop_entry(&key)
+    }
+
+    // join a shared budget, stamping accesses with its ticks from now on.
+    fn register(&mut self, ticks: Arc<AtomicU64>) {
+        self.ticks = Some(ticks);
+    }
+
+    // the next access tick of the shared budget, if registered with one.
+    fn tick(&self) -> u64 {
+        self.ticks
+            .as_ref()
+            .map_or(0, |ticks| ticks.fetch_add(1, Ordering::Relaxed))
+    }
+
+    // when the item `evict_for_budget` would evict was last accessed, and
+    // the bytes evicting it would free.
+    fn coldest(&self) -> Option<(u64, usize)> {
+        let (_, slot) = self.inner.iter().rev().find(|(_, slot)| !slot.pinned)?;
+        let charge = slot.size + slot.share.map_or(0, |id| self.shares.exclusive(id));
+        Some((slot.touched, charge))
+    }
+
+    // evict the least recently used unpinned item on behalf of a shared
+    // budget, whatever the eviction policy, returning whether there was one.
+    fn evict_for_budget(&mut self) -> bool {
+        let (k, slot) = match self.pop_lru_unpinned() {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        let cur_size = self.cur_size;
+        self.discharge(&k, &slot);
+        self.stats.eviction(cur_size - self.cur_size);
+        self.notify(k, slot.value, EvictionReason::Capacity);
+        self.stats.size(self.cur_size);
+        true
+    }
+
+    // stop charging for an item which has left the cache, other than by
+    // eviction.
+    fn discharge(&mut self, key: &K, slot: &Slot<V>) {
+        if slot.pinned {
+            self.pinned_size -= slot.size + slot.share.map_or(0, |id| self.shares.unpin(id));
+        }
+        self.cur_size -= slot.size + slot.share.map_or(0, |id| self.shares.release(id));
+        if let Some(policy) = self.policy.as_mut() {
+            policy.on_remove(key);
+        }
+    }
+
+    fn notify(&mut self, key: K, val: V, reason: EvictionReason) {
+        if let Some(listener) = self.listener.as_mut() {
+            listener.on_evict(key, val, reason);
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl<'a, K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> IntoIterator
+    for &'a MemoryLruCache<K, V, M, S>
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<K: Eq + Hash, V, M: Meter<K, V>, S: BuildHasher> IntoIterator for MemoryLruCache<K, V, M, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(mut self) -> IntoIter<K, V> {
+        // the underlying cache can only be popped from the LRU end, so
+        // collect the items and hand them out back to front.
+        let mut items = Vec::with_capacity(self.inner.len());
+        while let Some((k, slot)) = self.inner.pop_lru() {
+            items.push((k, slot.value));
+        }
 
-    impl ResidentSize for Vec<u8> {
-        fn resident_size(&self) -> usize {
-            self.len()
+        IntoIter {
+            inner: items.into_iter().rev(),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
     fn it_works() {
@@ -178,4 +1594,330 @@ mod tests {
 
         assert_eq!(Some(&vec![2u8, 3u8]), cache.get(&2));
     }
+
+    #[test]
+    fn listener_sees_replacements_and_evictions() {
+        use std::sync::{Arc, Mutex};
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = MemoryLruCache::new(4);
+        cache.set_eviction_listener({
+            let evicted = evicted.clone();
+            move |k, v: Vec<u8>, reason| evicted.lock().unwrap().push((k, v, reason))
+        });
+
+        cache.insert(1, vec![0u8, 1u8]);
+        cache.insert(1, vec![2u8, 3u8]);
+        cache.insert(2, vec![4u8, 5u8]);
+        cache.insert(3, vec![6u8, 7u8]);
+
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            vec![
+                (1, vec![0u8, 1u8], EvictionReason::Replaced),
+                (1, vec![2u8, 3u8], EvictionReason::Capacity),
+            ]
+        );
+        assert_eq!(cache.current_size(), 4);
+    }
+
+    #[test]
+    fn insert_returning_hands_back_displaced_items() {
+        let mut cache = MemoryLruCache::new(6);
+        cache.set_eviction_listener(|_, _, _| panic!("nothing is passed to the listener"));
+        cache.insert_returning(1, vec![1u8; 2]);
+        cache.insert_returning(2, vec![2u8; 2]);
+
+        let displaced = cache.insert_returning(1, vec![3u8; 3]);
+        assert_eq!(displaced.replaced, Some(vec![1u8; 2]));
+        assert!(displaced.evicted.is_empty());
+
+        let displaced = cache.insert_returning(3, vec![4u8; 3]);
+        assert_eq!(displaced.replaced, None);
+        assert_eq!(displaced.evicted, vec![(2, vec![2u8; 2])]);
+
+        cache.set_oversize_policy(OversizePolicy::Reject);
+        let displaced = cache.insert_returning(4, vec![5u8; 7]);
+        assert_eq!(displaced.rejected, Some((4, vec![5u8; 7])));
+        assert_eq!(cache.current_size(), 6);
+    }
+
+    #[test]
+    fn lookups_accept_borrowed_keys() {
+        let mut cache = MemoryLruCache::new(256);
+        cache.insert(String::from("a"), vec![0u8; 4]);
+        cache.insert(String::from("b"), vec![0u8; 2]);
+
+        assert!(cache.contains("a"));
+        assert_eq!(cache.peek("b"), Some(&vec![0u8; 2]));
+        assert_eq!(cache.get("a"), Some(&vec![0u8; 4]));
+        cache.with_mut("b", |v| v.unwrap().push(1));
+        assert_eq!(cache.current_size(), 7);
+        assert_eq!(cache.remove("a"), Some(vec![0u8; 4]));
+        assert_eq!(cache.current_size(), 3);
+    }
+
+    #[test]
+    fn custom_hasher() {
+        use std::collections::hash_map::RandomState;
+
+        let mut cache = MemoryLruCache::with_hasher(6, RandomState::new());
+        cache.insert("a", vec![0u8; 4]);
+        cache.insert("b", vec![0u8; 2]);
+        assert_eq!(cache.get("a"), Some(&vec![0u8; 4]));
+
+        cache.insert("c", vec![0u8; 1]);
+        assert!(!cache.contains("b"));
+        assert_eq!(cache.current_size(), 5);
+    }
+
+    #[test]
+    fn pinned_items_survive_eviction() {
+        let mut cache = MemoryLruCache::new(10);
+        cache.insert(1, vec![0u8; 4]);
+        cache.insert(2, vec![0u8; 3]);
+        assert_eq!(cache.pin(&1), Ok(true));
+        assert_eq!(cache.pin(&3), Ok(false));
+
+        cache.insert(3, vec![0u8; 5]);
+        assert!(cache.contains(&1));
+        assert!(!cache.contains(&2));
+        assert_eq!(cache.current_size(), 9);
+        assert_eq!(cache.pinned_size(), 4);
+
+        // the pin carries over to the replacement.
+        cache.insert(1, vec![0u8; 5]);
+        assert_eq!(cache.pinned_size(), 5);
+
+        assert!(cache.unpin(&1));
+        assert_eq!(cache.pinned_size(), 0);
+        cache.insert(4, vec![0u8; 5]);
+        assert!(!cache.contains(&3));
+        cache.insert(5, vec![0u8; 5]);
+        assert!(!cache.contains(&1));
+    }
+
+    #[test]
+    fn pinned_items_may_not_grow_past_the_budget() {
+        let mut cache = MemoryLruCache::new(10);
+        cache.insert(1, vec![0u8; 4]);
+        cache.pin(&1).unwrap();
+
+        let displaced = cache.insert_returning(1, vec![0u8; 50]);
+        assert_eq!(displaced.rejected, Some((1, vec![0u8; 50])));
+        assert_eq!(cache.peek(&1), Some(&vec![0u8; 4]));
+        assert_eq!(cache.pinned_size(), 4);
+
+        cache.with_mut(&1, |v| v.unwrap().extend([0u8; 96]));
+        assert!(!cache.is_pinned(&1));
+        assert_eq!(cache.pinned_size(), 0);
+    }
+
+    #[test]
+    fn try_insert_hands_back_values_overflowing_pins() {
+        let mut cache = MemoryLruCache::new(10);
+        cache.set_eviction_listener(|_, _, _| panic!("nothing is passed to the listener"));
+        cache.insert(1, vec![0u8; 4]);
+        cache.insert(2, vec![0u8; 4]);
+        cache.pin(&1).unwrap();
+        cache.pin(&2).unwrap();
+
+        let err = cache.try_insert(1, vec![0u8; 7]).unwrap_err();
+        assert_eq!((err.key, err.value.len()), (1, 7));
+        assert_eq!((err.size, err.pinned_size), (7, 4));
+        assert_eq!(cache.peek(&1), Some(&vec![0u8; 4]));
+        assert_eq!(cache.current_size(), 8);
+    }
+
+    #[test]
+    fn pinned_items_keep_their_place() {
+        let mut cache = MemoryLruCache::new(10);
+        cache.insert(1, vec![0u8; 3]);
+        cache.insert(2, vec![0u8; 3]);
+        cache.insert(3, vec![0u8; 3]);
+        cache.pin(&1).unwrap();
+
+        cache.insert(4, vec![0u8; 3]);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), [4, 3, 1]);
+
+        cache.unpin(&1);
+        cache.insert(5, vec![0u8; 3]);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), [5, 4, 3]);
+    }
+
+    #[test]
+    fn pinned_items_alone_may_not_exceed_the_budget() {
+        let mut cache = MemoryLruCache::new(10);
+        cache.insert(1, vec![0u8; 4]);
+        cache.insert(2, vec![0u8; 4]);
+        cache.insert(3, vec![0u8; 1]);
+        cache.pin(&1).unwrap();
+        cache.pin(&2).unwrap();
+        cache.pin(&3).unwrap();
+
+        // shrinking unpins the least recently used until the rest fit.
+        assert_eq!(cache.set_max_size_returning(5), vec![(1, vec![0u8; 4])]);
+        assert_eq!((cache.current_size(), cache.pinned_size()), (5, 5));
+        assert!(cache.is_pinned(&2) && cache.is_pinned(&3));
+    }
+
+    #[test]
+    fn get_mut_spares_the_released_item() {
+        let mut cache = MemoryLruCache::new(10);
+        cache.insert(1, vec![0u8; 2]);
+        cache.pin(&1).unwrap();
+        cache.insert(2, vec![0u8; 4]);
+        cache.insert(3, vec![0u8; 2]);
+
+        // everything else unpinned goes, but the grown item stays.
+        cache.get_mut(&3).unwrap().extend([0u8; 8]);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec![3, 1]);
+        assert_eq!(cache.current_size(), 12);
+
+        // it is only spared while released.
+        cache.insert(4, vec![0u8; 1]);
+        assert!(!cache.contains(&3));
+        assert_eq!(cache.current_size(), 3);
+
+        let mut v = cache.get_mut(&4).unwrap();
+        v.extend([0u8; 9]);
+        v.allow_eviction();
+        drop(v);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec![1]);
+
+        // `with_mut` does not spare it either.
+        cache.insert(5, vec![0u8; 2]);
+        cache.with_mut(&5, |v| v.unwrap().extend([0u8; 10]));
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn with_mut_returning_reports_the_outcome() {
+        let mut cache = MemoryLruCache::new(10);
+        cache.set_eviction_listener(|_, _, _| panic!("nothing is passed to the listener"));
+        cache.insert(1, vec![0u8; 4]);
+        cache.insert(2, vec![0u8; 4]);
+
+        let grow = |v: Option<&mut Vec<u8>>| v.unwrap().extend([0u8; 8]);
+        let mutated = cache.with_mut_returning(&2, Retention::Protected, grow);
+        assert!(mutated.retained);
+        assert_eq!(mutated.evicted, vec![(1, vec![0u8; 4])]);
+        assert_eq!(cache.current_size(), 12);
+
+        let mutated = cache.with_mut_returning(&2, Retention::Evictable, |v| v.unwrap().len());
+        assert_eq!(mutated.result, 12);
+        assert!(!mutated.retained);
+        assert_eq!(mutated.evicted, vec![(2, vec![0u8; 12])]);
+        assert!(cache.is_empty());
+
+        let mutated = cache.with_mut_returning(&2, Retention::Protected, |v| v.is_none());
+        assert!(mutated.result && !mutated.retained);
+    }
+
+    #[test]
+    fn panicking_predicate_leaves_cache_consistent() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut cache = MemoryLruCache::new(256);
+        cache.insert(1, vec![0u8; 10]);
+        cache.insert(2, vec![0u8; 20]);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            cache.retain(|&k, _| if k == 1 { panic!("predicate") } else { false })
+        }));
+        assert!(res.is_err());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.current_size(), 30);
+
+        cache.retain(|&k, _| k == 1);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(cache.current_size(), 10);
+    }
+
+    #[test]
+    fn oversized_items_follow_policy() {
+        let mut cache = MemoryLruCache::new(8);
+        cache.insert(1, vec![0u8; 4]);
+        cache.insert(2, vec![0u8; 4]);
+
+        let err = cache.try_insert(3, vec![0u8; 9]).unwrap_err();
+        assert_eq!((err.key, err.value.len(), err.size), (3, 9, 9));
+        assert!(cache.try_insert(3, vec![0u8; 2]).is_ok());
+        assert_eq!(cache.len(), 2);
+
+        cache.set_oversize_policy(OversizePolicy::Reject);
+        cache.insert(2, vec![0u8; 9]);
+        assert_eq!(cache.peek(&2), Some(&vec![0u8; 4]));
+
+        cache.set_oversize_policy(OversizePolicy::Bypass);
+        cache.insert(2, vec![0u8; 9]);
+        assert!(!cache.contains(&2));
+        assert_eq!(cache.current_size(), 2);
+
+        cache.set_oversize_policy(OversizePolicy::Admit);
+        cache.insert(2, vec![0u8; 9]);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn resizing_evicts_only_when_shrinking() {
+        use std::sync::Mutex;
+
+        let mut cache = MemoryLruCache::new(8);
+        for i in 0..4u8 {
+            cache.insert(i, vec![i; 2]);
+        }
+
+        assert!(cache.set_max_size_returning(16).is_empty());
+        cache.insert(4, vec![4u8; 2]);
+        assert_eq!(cache.len(), 5);
+
+        let evicted = cache.set_max_size_returning(5);
+        assert_eq!(
+            evicted,
+            vec![(0, vec![0u8; 2]), (1, vec![1u8; 2]), (2, vec![2u8; 2])]
+        );
+        assert_eq!(cache.max_size(), 5);
+        assert_eq!(cache.current_size(), 4);
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        cache.set_eviction_listener({
+            let evicted = evicted.clone();
+            move |k, _, reason| {
+                assert_eq!(reason, EvictionReason::Capacity);
+                evicted.lock().unwrap().push(k);
+            }
+        });
+        cache.set_max_size(2);
+        assert_eq!(*evicted.lock().unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn removal_keeps_size_in_step() {
+        let mut cache = MemoryLruCache::new(256);
+        for i in 0..5u8 {
+            cache.insert(i, vec![i; i as usize + 1]);
+        }
+        assert_eq!(cache.current_size(), 15);
+
+        assert_eq!(cache.remove(&2), Some(vec![2u8; 3]));
+        assert_eq!(cache.remove(&2), None);
+        assert_eq!(cache.peek_lru(), Some((&0, &vec![0u8])));
+        assert_eq!(cache.pop_lru(), Some((0, vec![0u8])));
+        assert_eq!(cache.current_size(), 11);
+
+        cache.retain(|k, _| k % 2 == 1);
+        assert_eq!(cache.current_size(), 6);
+        assert_eq!(cache.peek_lru(), Some((&1, &vec![1u8; 2])));
+
+        let drained: Vec<_> = cache.drain().map(|(k, _)| k).collect();
+        assert_eq!(drained, vec![1, 3]);
+        assert!(cache.is_empty());
+        assert_eq!(cache.current_size(), 0);
+
+        cache.insert(7, vec![0u8; 7]);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.current_size(), 0);
+    }
 }
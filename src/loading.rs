@@ -0,0 +1,331 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! A cache which loads missing items asynchronously, coalescing concurrent
+//! loads of the same key.
+
+use crate::concurrent::lock;
+use crate::{MemoryLruCache, Meter, ResidentSize, ValueSize};
+
+use std::any::Any;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// A thread-safe cache which loads missing items through `get_or_load`.
+///
+/// When several tasks miss on the same key at once, only the first runs its
+/// loader; the others wait for and share its result, whether a value or an
+/// error. Errors are never cached, so the next lookup loads afresh.
+///
+/// The cache does not depend on any particular async runtime.
+pub struct LoadingMemoryLruCache<K, V, M = ValueSize> {
+    cache: Mutex<MemoryLruCache<K, V, M>>,
+    loads: Mutex<HashMap<K, Arc<Load<V>>>>,
+}
+
+impl<K: Clone + Eq + Hash, V: ResidentSize> LoadingMemoryLruCache<K, V> {
+    /// Create a new cache with a maximum cumulative size of values.
+    pub fn new(max_size: usize) -> Self {
+        LoadingMemoryLruCache::with_meter(max_size, ValueSize)
+    }
+}
+
+impl<K: Clone + Eq + Hash, V, M: Meter<K, V>> LoadingMemoryLruCache<K, V, M> {
+    /// Create a new cache with a maximum cumulative size of entries, as
+    /// measured by the given meter.
+    pub fn with_meter(max_size: usize, meter: M) -> Self {
+        LoadingMemoryLruCache {
+            cache: Mutex::new(MemoryLruCache::with_meter(max_size, meter)),
+            loads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a copy of the item under the key, loading and inserting it if it
+    /// is missing. If a load of the key is already in progress, wait for it
+    /// instead of calling the loader.
+    ///
+    /// Errors are shared with every task waiting on the same load, so they
+    /// are returned behind an `Arc`. A task whose loader has a different
+    /// error type than the one in progress loads the item itself if that
+    /// load fails. If the task running a load is dropped before it finishes,
+    /// one of those waiting takes over.
+    pub async fn get_or_load<F, Fut, E>(&self, key: K, loader: F) -> Result<V, Arc<E>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+        E: Send + Sync + 'static,
+        V: Clone,
+    {
+        let mut loader = Some(loader);
+
+        loop {
+            let (load, leading) = {
+                // the cache is checked under the lock on loads, which is held
+                // by a finishing load while it inserts, so a load can't finish
+                // unnoticed between the two.
+                let mut loads = lock(&self.loads);
+                if let Some(value) = lock(&self.cache).get(&key) {
+                    return Ok(value.clone());
+                }
+
+                match loads.get(&key) {
+                    Some(load) => (load.clone(), false),
+                    None => {
+                        let load = Arc::new(Load::new());
+                        loads.insert(key.clone(), load.clone());
+                        (load, true)
+                    }
+                }
+            };
+
+            if leading {
+                let leader = Leader {
+                    cache: self,
+                    key: &key,
+                    load: &load,
+                    finished: false,
+                };
+
+                let loader = loader
+                    .take()
+                    .expect("a task leads at most one load, after which it returns; qed");
+                return match loader().await {
+                    Ok(value) => {
+                        leader.finish(Outcome::Loaded(value.clone()), Some(value.clone()));
+                        Ok(value)
+                    }
+                    Err(err) => {
+                        let err = Arc::new(err);
+                        leader.finish(Outcome::Failed(err.clone()), None);
+                        Err(err)
+                    }
+                };
+            }
+
+            match (Wait { load: &load }).await {
+                Outcome::Loaded(value) => return Ok(value),
+                Outcome::Failed(err) => {
+                    if let Ok(err) = err.downcast::<E>() {
+                        return Err(err);
+                    }
+                }
+                Outcome::Abandoned => {}
+            }
+        }
+    }
+
+    /// Get a copy of an item in the cache, updating its recency. Never
+    /// loads the item.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        lock(&self.cache).get(key).cloned()
+    }
+
+    /// Insert an item, replacing any value in the cache. A load of the same
+    /// key in progress still inserts its own value when it finishes.
+    pub fn insert(&self, key: K, val: V) {
+        lock(&self.cache).insert(key, val)
+    }
+
+    /// Remove an item from the cache, returning its value if it was present.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        lock(&self.cache).remove(key)
+    }
+
+    /// Currently-used size of entries in bytes, as measured by the meter.
+    pub fn current_size(&self) -> usize {
+        lock(&self.cache).current_size()
+    }
+
+    /// Returns the number of key-value pairs that are currently in the cache.
+    pub fn len(&self) -> usize {
+        lock(&self.cache).len()
+    }
+
+    /// Returns a bool indicating whether the cache is empty or not.
+    pub fn is_empty(&self) -> bool {
+        lock(&self.cache).is_empty()
+    }
+}
+
+// the shared state of a load in progress.
+struct Load<V> {
+    state: Mutex<LoadState<V>>,
+}
+
+enum LoadState<V> {
+    Pending(Vec<Waker>),
+    Done(Outcome<V>),
+}
+
+#[derive(Clone)]
+enum Outcome<V> {
+    Loaded(V),
+    // the loader's error, whose type only the tasks which share it know.
+    Failed(Arc<dyn Any + Send + Sync>),
+    // the task running the load was dropped before it finished.
+    Abandoned,
+}
+
+impl<V> Load<V> {
+    fn new() -> Self {
+        Load {
+            state: Mutex::new(LoadState::Pending(Vec::new())),
+        }
+    }
+
+    fn complete(&self, outcome: Outcome<V>) {
+        let state = mem::replace(&mut *lock(&self.state), LoadState::Done(outcome));
+        if let LoadState::Pending(wakers) = state {
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+}
+
+// resolves with the outcome of a load once it finishes.
+struct Wait<'a, V> {
+    load: &'a Load<V>,
+}
+
+impl<'a, V: Clone> Future for Wait<'a, V> {
+    type Output = Outcome<V>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Outcome<V>> {
+        match &mut *lock(&self.load.state) {
+            LoadState::Pending(wakers) => {
+                if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            LoadState::Done(outcome) => Poll::Ready(outcome.clone()),
+        }
+    }
+}
+
+// the task running a load. if dropped before finishing, waiters are told
+// the load was abandoned.
+struct Leader<'a, K: Clone + Eq + Hash, V, M: Meter<K, V>> {
+    cache: &'a LoadingMemoryLruCache<K, V, M>,
+    key: &'a K,
+    load: &'a Load<V>,
+    finished: bool,
+}
+
+impl<'a, K: Clone + Eq + Hash, V, M: Meter<K, V>> Leader<'a, K, V, M> {
+    fn finish(mut self, outcome: Outcome<V>, value: Option<V>) {
+        self.finished = true;
+        self.complete(outcome, value);
+    }
+
+    fn complete(&self, outcome: Outcome<V>, value: Option<V>) {
+        {
+            let mut loads = lock(&self.cache.loads);
+            if let Some(value) = value {
+                lock(&self.cache.cache).insert(self.key.clone(), value);
+            }
+            loads.remove(self.key);
+        }
+
+        self.load.complete(outcome);
+    }
+}
+
+impl<'a, K: Clone + Eq + Hash, V, M: Meter<K, V>> Drop for Leader<'a, K, V, M> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.complete(Outcome::Abandoned, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use futures::future::{join, join_all, FutureExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn concurrent_loads_are_coalesced() {
+        let cache = LoadingMemoryLruCache::new(256);
+        let calls = AtomicUsize::new(0);
+        let (tx, rx) = oneshot::channel::<()>();
+        let rx = rx.shared();
+
+        let lookups = join_all((0..3).map(|_| {
+            cache.get_or_load("a", || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let rx = rx.clone();
+                async move {
+                    rx.await.unwrap();
+                    Ok::<_, ()>(vec![0u8; 8])
+                }
+            })
+        }));
+
+        // every lookup is waiting by the time the load is let through.
+        let (results, _) = block_on(join(lookups, async move { tx.send(()).unwrap() }));
+        assert!(results.into_iter().all(|r| r == Ok(vec![0u8; 8])));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.current_size(), 8);
+    }
+
+    #[test]
+    fn errors_reach_every_waiter_and_are_not_cached() {
+        let cache = LoadingMemoryLruCache::<_, Vec<u8>>::new(256);
+        let (tx, rx) = oneshot::channel::<()>();
+        let rx = rx.shared();
+
+        let lookups = join_all((0..2).map(|_| {
+            let rx = rx.clone();
+            cache.get_or_load("a", || async move {
+                rx.await.unwrap();
+                Err("unavailable")
+            })
+        }));
+
+        let (results, _) = block_on(join(lookups, async move { tx.send(()).unwrap() }));
+        assert!(results
+            .iter()
+            .all(|r| r.as_ref().unwrap_err().as_ref() == &"unavailable"));
+        assert!(cache.is_empty());
+
+        let loaded = block_on(cache.get_or_load("a", || async { Ok::<_, ()>(vec![1u8; 2]) }));
+        assert_eq!(loaded, Ok(vec![1u8; 2]));
+        assert_eq!(cache.get("a"), Some(vec![1u8; 2]));
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Strategies for measuring the memory an entry is charged against the budget.
+
+use crate::ResidentSize;
+
+use std::mem;
+use std::sync::Arc;
+
+/// Measures the size charged against the cache budget for a single entry.
+pub trait Meter<K, V> {
+    /// Return the size of the entry with the given key and value. Like
+    /// `ResidentSize`, this must remain stable unless the value is mutated.
+    fn measure(&self, key: &K, value: &V) -> usize;
+
+    /// Return the allocation the entry may share with other entries, if any.
+    /// It is charged once, on top of `measure`, for as long as any entry in
+    /// the cache refers to it; `measure` should leave it out. Likewise, it
+    /// counts once toward `pinned_size` while any pinned entry refers to it.
+    fn shared(&self, _key: &K, _value: &V) -> Option<SharedAllocation> {
+        None
+    }
+}
+
+/// A heap allocation which several entries may refer to, such as the
+/// contents of an `Arc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedAllocation {
+    /// Identifies the allocation among all those in the cache, typically its
+    /// address.
+    pub id: usize,
+    /// The size of the allocation.
+    pub size: usize,
+}
+
+/// Charges only the resident size of values. This is the default meter.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ValueSize;
+
+impl<K, V: ResidentSize> Meter<K, V> for ValueSize {
+    fn measure(&self, _key: &K, value: &V) -> usize {
+        value.resident_size()
+    }
+}
+
+/// Charges the resident size of both keys and values, plus a fixed overhead
+/// per entry for the node kept by the underlying `LruCache`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyValueSize;
+
+impl<K: ResidentSize, V: ResidentSize> Meter<K, V> for KeyValueSize {
+    fn measure(&self, key: &K, value: &V) -> usize {
+        key.resident_size() + value.resident_size() + entry_overhead::<K, V>()
+    }
+}
+
+/// Charges the contents of `Arc` values once per allocation, however many
+/// entries hold a handle to it. Allocations also held outside the cache are
+/// still charged in full while any entry refers to them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SharedArcSize;
+
+impl<K, T: ResidentSize + ?Sized> Meter<K, Arc<T>> for SharedArcSize {
+    fn measure(&self, _key: &K, _value: &Arc<T>) -> usize {
+        0
+    }
+
+    fn shared(&self, _key: &K, value: &Arc<T>) -> Option<SharedAllocation> {
+        Some(SharedAllocation {
+            id: Arc::as_ptr(value) as *const () as usize,
+            size: value.resident_size(),
+        })
+    }
+}
+
+/// Any weigher closure is a meter, for types which cannot implement
+/// `ResidentSize` themselves.
+impl<K, V, F: Fn(&K, &V) -> usize> Meter<K, V> for F {
+    fn measure(&self, key: &K, value: &V) -> usize {
+        self(key, value)
+    }
+}
+
+/// The fixed cost of a single entry in the underlying `LruCache`: the inline
+/// key and value, the linked-list pointers and the hash table slot.
+fn entry_overhead<K, V>() -> usize {
+    mem::size_of::<K>() + mem::size_of::<V>() + 4 * mem::size_of::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryLruCache, PinError};
+
+    #[test]
+    fn weigher_closures_measure_entries() {
+        struct Foreign(u32);
+
+        let mut cache = MemoryLruCache::with_weigher(10, |_: &u8, v: &Arc<Foreign>| v.0 as usize);
+        cache.insert(1, Arc::new(Foreign(4)));
+        cache.insert(2, Arc::new(Foreign(5)));
+        assert_eq!(cache.current_size(), 9);
+
+        cache.insert(3, Arc::new(Foreign(3)));
+        assert_eq!(cache.current_size(), 8);
+        assert!(!cache.contains(&1));
+    }
+
+    #[test]
+    fn shared_arcs_are_charged_once() {
+        let block = Arc::new(vec![0u8; 100]);
+        let mut cache = MemoryLruCache::with_meter(1000, SharedArcSize);
+
+        cache.insert("a", block.clone());
+        cache.insert("b", block.clone());
+        assert_eq!(cache.current_size(), 24 + 100);
+
+        cache.insert("c", Arc::new(vec![0u8; 10]));
+        assert_eq!(cache.current_size(), 2 * 24 + 110);
+
+        // the block stays charged until the last entry holding it goes.
+        cache.remove("a");
+        assert_eq!(cache.current_size(), 2 * 24 + 110);
+        cache.insert("b", Arc::new(vec![0u8; 1]));
+        assert_eq!(cache.current_size(), 2 * 24 + 11);
+
+        cache.with_mut("b", |v| Arc::get_mut(v.unwrap()).unwrap().extend([0u8; 99]));
+        assert_eq!(cache.current_size(), 2 * 24 + 110);
+    }
+
+    #[test]
+    fn shared_arcs_count_toward_the_oversize_check() {
+        let mut cache = MemoryLruCache::with_meter(100, SharedArcSize);
+        cache.insert(1, Arc::new(vec![0u8; 10]));
+
+        let err = cache.try_insert(2, Arc::new(vec![0u8; 1000])).unwrap_err();
+        assert_eq!(err.size, 24 + 1000);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn shared_arcs_count_toward_pinned_size() {
+        let block = Arc::new(vec![0u8; 50]);
+        let mut cache = MemoryLruCache::with_meter(200, SharedArcSize);
+        cache.insert(1, block.clone());
+        cache.insert(2, block);
+        cache.insert(3, Arc::new(vec![0u8; 10]));
+
+        // the block is pinned once, while either item holding it is.
+        cache.pin(&1).unwrap();
+        cache.pin(&2).unwrap();
+        assert_eq!(cache.pinned_size(), 24 + 50);
+        cache.unpin(&1);
+        assert_eq!(cache.pinned_size(), 24 + 50);
+        cache.unpin(&2);
+        assert_eq!(cache.pinned_size(), 0);
+    }
+
+    #[test]
+    fn pinned_arcs_may_not_grow_past_the_budget() {
+        let mut cache = MemoryLruCache::with_meter(100, SharedArcSize);
+        cache.insert(1, Arc::new(vec![0u8; 30]));
+        cache.insert(2, Arc::new(vec![0u8; 10]));
+        cache.pin(&1).unwrap();
+        cache.pin(&2).unwrap();
+        assert_eq!(cache.pinned_size(), 2 * 24 + 40);
+
+        *Arc::get_mut(&mut cache.get_mut(&2).unwrap()).unwrap() = vec![0u8; 30];
+        assert!(!cache.is_pinned(&2));
+        assert_eq!(cache.pinned_size(), 24 + 30);
+        assert_eq!(
+            cache.pin(&2),
+            Err(PinError {
+                size: 24 + 30,
+                pinned_size: 2 * 24 + 60,
+                max_size: 100
+            })
+        );
+    }
+
+    #[test]
+    fn key_value_size_counts_keys_and_overhead() {
+        let overhead = entry_overhead::<Vec<u8>, Vec<u8>>();
+        let mut cache = MemoryLruCache::with_meter(2 * (20 + overhead), KeyValueSize);
+
+        cache.insert(vec![0u8; 10], vec![0u8; 10]);
+        assert_eq!(cache.current_size(), 20 + overhead);
+
+        cache.insert(vec![1u8; 15], vec![1u8; 5]);
+        assert_eq!(cache.current_size(), 2 * (20 + overhead));
+        assert_eq!(cache.len(), 2);
+
+        // replacing a value keeps charging the key once.
+        cache.insert(vec![1u8; 15], vec![1u8; 6]);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.current_size(), 21 + overhead);
+    }
+}
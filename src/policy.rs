@@ -0,0 +1,959 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Eviction policies deciding which item makes room when the cache is over
+//! its memory budget.
+//!
+//! By default a `MemoryLruCache` evicts its least recently used item. The
+//! policies here instead keep their own bookkeeping of keys and the bytes
+//! each item is charged, and are installed with
+//! `MemoryLruCache::set_eviction_policy`.
+//!
+//! `Slru`, `TwoQueue`, `S3Fifo` and `WTinyLfu` resist scans: items seen only
+//! once make way before items which have proven popular. `GreedyDualSize`
+//! instead weighs items by their size, evicting those worth the least per
+//! byte.
+//!
+//! Whatever the policy, the cache's iterators, `peek_lru` and `pop_lru` keep
+//! following recency.
+
+use lru::LruCache;
+
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+
+/// Decides which item to evict when the cache is over its memory budget.
+///
+/// The cache tells the policy about every key entering and leaving it, along
+/// with the size each item is charged, and asks it for a victim whenever an
+/// item must go. Pinned items may not be chosen, so the cache also says which
+/// items are evictable.
+pub trait EvictionPolicy<K> {
+    /// Called with the cache's memory budget when the policy is installed and
+    /// whenever the budget changes.
+    fn set_capacity(&mut self, max_size: usize);
+
+    /// Called when an item is inserted under a key not in the cache.
+    fn on_insert(&mut self, key: &K, size: usize);
+
+    /// Called when an item is looked up or its value replaced.
+    fn on_access(&mut self, key: &K);
+
+    /// Called when the size charged for an item changes.
+    fn on_resize(&mut self, key: &K, size: usize);
+
+    /// Called when an item leaves the cache other than by being chosen as a
+    /// victim.
+    fn on_remove(&mut self, key: &K);
+
+    /// Called when every item is removed from the cache at once.
+    fn clear(&mut self);
+
+    /// Choose the next item to evict among those for which `evictable`
+    /// returns true, and stop tracking it. Items passed over are to be left
+    /// as they were. Returns `None` only if no tracked item is evictable.
+    fn victim(&mut self, evictable: &dyn Fn(&K) -> bool) -> Option<K>;
+}
+
+impl<K, P: EvictionPolicy<K> + ?Sized> EvictionPolicy<K> for Box<P> {
+    fn set_capacity(&mut self, max_size: usize) {
+        (**self).set_capacity(max_size)
+    }
+
+    fn on_insert(&mut self, key: &K, size: usize) {
+        (**self).on_insert(key, size)
+    }
+
+    fn on_access(&mut self, key: &K) {
+        (**self).on_access(key)
+    }
+
+    fn on_resize(&mut self, key: &K, size: usize) {
+        (**self).on_resize(key, size)
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        (**self).on_remove(key)
+    }
+
+    fn clear(&mut self) {
+        (**self).clear()
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(&K) -> bool) -> Option<K> {
+        (**self).victim(evictable)
+    }
+}
+
+// per-item bookkeeping kept by the policies.
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    size: usize,
+    // a small saturating access counter, used by S3-FIFO.
+    freq: u8,
+}
+
+impl Node {
+    fn new(size: usize) -> Self {
+        Node { size, freq: 0 }
+    }
+}
+
+// keys in order of arrival or use, along with the bytes they are charged.
+// the oldest key is at the front.
+struct Queue<K> {
+    entries: LruCache<K, Node>,
+    size: usize,
+}
+
+impl<K: Hash + Eq> Queue<K> {
+    fn new() -> Self {
+        Queue {
+            entries: LruCache::unbounded(),
+            size: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn push(&mut self, key: K, node: Node) {
+        self.size += node.size;
+        if let Some(old) = self.entries.put(key, node) {
+            self.size -= old.size;
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<(K, Node)> {
+        let (key, node) = self.entries.pop_lru()?;
+        self.size -= node.size;
+        Some((key, node))
+    }
+
+    // the oldest key for which `evictable` returns true.
+    fn peek_oldest_where(&self, evictable: &dyn Fn(&K) -> bool) -> Option<&K> {
+        self.entries
+            .iter()
+            .rev()
+            .map(|(k, _)| k)
+            .find(|k| evictable(k))
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Node> {
+        let node = self.entries.pop(key)?;
+        self.size -= node.size;
+        Some(node)
+    }
+
+    // move the key to the back, returning whether it was present.
+    fn touch(&mut self, key: &K) -> bool {
+        self.entries.get(key).is_some()
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut Node> {
+        self.entries.peek_mut(key)
+    }
+
+    // returns whether the key was present.
+    fn resize(&mut self, key: &K, size: usize) -> bool {
+        match self.entries.peek_mut(key) {
+            Some(node) => {
+                self.size -= node.size;
+                self.size += size;
+                node.size = size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // drop the oldest keys until the queue fits in `cap` bytes.
+    fn truncate(&mut self, cap: usize) {
+        while self.size > cap && self.pop_oldest().is_some() {}
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+}
+
+impl<K: Clone + Hash + Eq> Queue<K> {
+    // remove the oldest key for which `evictable` returns true, leaving the
+    // keys before it in place.
+    fn pop_oldest_where(&mut self, evictable: &dyn Fn(&K) -> bool) -> Option<(K, Node)> {
+        let key = self.peek_oldest_where(evictable)?.clone();
+        let node = self.remove(&key)?;
+        Some((key, node))
+    }
+}
+
+// a share of the budget, in percent.
+fn share(max_size: usize, percent: usize) -> usize {
+    max_size / 100 * percent + max_size % 100 * percent / 100
+}
+
+// move the oldest protected keys back to probation until the protected
+// segment fits its share.
+fn demote_overflow<K: Hash + Eq>(protected: &mut Queue<K>, probation: &mut Queue<K>, cap: usize) {
+    while protected.size > cap {
+        match protected.pop_oldest() {
+            Some((key, node)) => probation.push(key, node),
+            None => break,
+        }
+    }
+}
+
+/// Segmented LRU. New items enter a probationary segment and are promoted to
+/// a protected segment, holding up to 80% of the budget, when accessed again.
+/// Victims are taken from the probationary segment first.
+pub struct Slru<K> {
+    probation: Queue<K>,
+    protected: Queue<K>,
+    protected_cap: usize,
+}
+
+impl<K: Clone + Hash + Eq> Slru<K> {
+    /// Create the policy.
+    pub fn new() -> Self {
+        Slru {
+            probation: Queue::new(),
+            protected: Queue::new(),
+            protected_cap: 0,
+        }
+    }
+}
+
+impl<K: Clone + Hash + Eq> Default for Slru<K> {
+    fn default() -> Self {
+        Slru::new()
+    }
+}
+
+impl<K: Clone + Hash + Eq> EvictionPolicy<K> for Slru<K> {
+    fn set_capacity(&mut self, max_size: usize) {
+        self.protected_cap = share(max_size, 80);
+        demote_overflow(&mut self.protected, &mut self.probation, self.protected_cap);
+    }
+
+    fn on_insert(&mut self, key: &K, size: usize) {
+        self.probation.push(key.clone(), Node::new(size));
+    }
+
+    fn on_access(&mut self, key: &K) {
+        if self.protected.touch(key) {
+            return;
+        }
+
+        if let Some(node) = self.probation.remove(key) {
+            self.protected.push(key.clone(), node);
+            demote_overflow(&mut self.protected, &mut self.probation, self.protected_cap);
+        }
+    }
+
+    fn on_resize(&mut self, key: &K, size: usize) {
+        if !self.probation.resize(key, size) && self.protected.resize(key, size) {
+            demote_overflow(&mut self.protected, &mut self.probation, self.protected_cap);
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if self.probation.remove(key).is_none() {
+            self.protected.remove(key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.probation.clear();
+        self.protected.clear();
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(&K) -> bool) -> Option<K> {
+        self.probation
+            .pop_oldest_where(evictable)
+            .or_else(|| self.protected.pop_oldest_where(evictable))
+            .map(|(key, _)| key)
+    }
+}
+
+/// The 2Q policy. New items enter a FIFO holding around 25% of the budget;
+/// those evicted from it are remembered, up to 50% of the budget, and go
+/// straight to the main LRU queue if inserted again. Accesses while in the
+/// FIFO do not count.
+pub struct TwoQueue<K> {
+    recent: Queue<K>,
+    frequent: Queue<K>,
+    ghost: Queue<K>,
+    recent_cap: usize,
+    ghost_cap: usize,
+}
+
+impl<K: Clone + Hash + Eq> TwoQueue<K> {
+    /// Create the policy.
+    pub fn new() -> Self {
+        TwoQueue {
+            recent: Queue::new(),
+            frequent: Queue::new(),
+            ghost: Queue::new(),
+            recent_cap: 0,
+            ghost_cap: 0,
+        }
+    }
+}
+
+impl<K: Clone + Hash + Eq> Default for TwoQueue<K> {
+    fn default() -> Self {
+        TwoQueue::new()
+    }
+}
+
+impl<K: Clone + Hash + Eq> EvictionPolicy<K> for TwoQueue<K> {
+    fn set_capacity(&mut self, max_size: usize) {
+        self.recent_cap = share(max_size, 25);
+        self.ghost_cap = share(max_size, 50);
+        self.ghost.truncate(self.ghost_cap);
+    }
+
+    fn on_insert(&mut self, key: &K, size: usize) {
+        if self.ghost.remove(key).is_some() {
+            self.frequent.push(key.clone(), Node::new(size));
+        } else {
+            self.recent.push(key.clone(), Node::new(size));
+        }
+    }
+
+    fn on_access(&mut self, key: &K) {
+        self.frequent.touch(key);
+    }
+
+    fn on_resize(&mut self, key: &K, size: usize) {
+        if !self.recent.resize(key, size) {
+            self.frequent.resize(key, size);
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if self.recent.remove(key).is_none() {
+            self.frequent.remove(key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.recent.clear();
+        self.frequent.clear();
+        self.ghost.clear();
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(&K) -> bool) -> Option<K> {
+        if self.recent.size <= self.recent_cap {
+            if let Some((key, _)) = self.frequent.pop_oldest_where(evictable) {
+                return Some(key);
+            }
+        }
+
+        match self.recent.pop_oldest_where(evictable) {
+            Some((key, node)) => {
+                self.ghost.push(key.clone(), node);
+                self.ghost.truncate(self.ghost_cap);
+                Some(key)
+            }
+            None => self
+                .frequent
+                .pop_oldest_where(evictable)
+                .map(|(key, _)| key),
+        }
+    }
+}
+
+/// The S3-FIFO policy. New items enter a small FIFO holding around 10% of
+/// the budget, and move on to the main FIFO only if accessed while there.
+/// Items leaving the small FIFO unaccessed are remembered, up to 90% of the
+/// budget, and go straight to the main FIFO if inserted again. Items in the
+/// main FIFO are given another pass for each access, up to three.
+pub struct S3Fifo<K> {
+    small: Queue<K>,
+    main: Queue<K>,
+    ghost: Queue<K>,
+    small_cap: usize,
+    ghost_cap: usize,
+}
+
+impl<K: Clone + Hash + Eq> S3Fifo<K> {
+    /// Create the policy.
+    pub fn new() -> Self {
+        S3Fifo {
+            small: Queue::new(),
+            main: Queue::new(),
+            ghost: Queue::new(),
+            small_cap: 0,
+            ghost_cap: 0,
+        }
+    }
+}
+
+impl<K: Clone + Hash + Eq> Default for S3Fifo<K> {
+    fn default() -> Self {
+        S3Fifo::new()
+    }
+}
+
+const S3_FIFO_MAX_FREQ: u8 = 3;
+
+impl<K: Clone + Hash + Eq> EvictionPolicy<K> for S3Fifo<K> {
+    fn set_capacity(&mut self, max_size: usize) {
+        self.small_cap = share(max_size, 10);
+        self.ghost_cap = share(max_size, 90);
+        self.ghost.truncate(self.ghost_cap);
+    }
+
+    fn on_insert(&mut self, key: &K, size: usize) {
+        if self.ghost.remove(key).is_some() {
+            self.main.push(key.clone(), Node::new(size));
+        } else {
+            self.small.push(key.clone(), Node::new(size));
+        }
+    }
+
+    fn on_access(&mut self, key: &K) {
+        let node = match self.small.get_mut(key) {
+            Some(node) => Some(node),
+            None => self.main.get_mut(key),
+        };
+
+        if let Some(node) = node {
+            node.freq = (node.freq + 1).min(S3_FIFO_MAX_FREQ);
+        }
+    }
+
+    fn on_resize(&mut self, key: &K, size: usize) {
+        if !self.small.resize(key, size) {
+            self.main.resize(key, size);
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if self.small.remove(key).is_none() {
+            self.main.remove(key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.small.clear();
+        self.main.clear();
+        self.ghost.clear();
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(&K) -> bool) -> Option<K> {
+        // every pass either evicts, moves an item from the small FIFO to the
+        // main one, or spends an access of an item in the main FIFO.
+        loop {
+            let main = match self.small.size > self.small_cap {
+                true => None,
+                false => self.main.pop_oldest_where(evictable),
+            };
+            let (key, node) = match main {
+                Some(entry) => entry,
+                None => match self.small.pop_oldest_where(evictable) {
+                    Some((key, node)) => {
+                        if node.freq > 0 {
+                            self.main.push(key, Node::new(node.size));
+                            continue;
+                        }
+
+                        self.ghost.push(key.clone(), node);
+                        self.ghost.truncate(self.ghost_cap);
+                        return Some(key);
+                    }
+                    None => self.main.pop_oldest_where(evictable)?,
+                },
+            };
+
+            if node.freq > 0 {
+                let freq = node.freq - 1;
+                self.main.push(key, Node { freq, ..node });
+                continue;
+            }
+
+            return Some(key);
+        }
+    }
+}
+
+/// The W-TinyLFU policy. New items enter a small LRU window holding around
+/// 1% of the budget. Items leaving the window join a segmented LRU holding
+/// the rest of the budget if there is room, and otherwise only if they have
+/// been seen more often than the item they would displace. How often items
+/// are seen is estimated by a compact frequency sketch, which forgets old
+/// counts over time.
+pub struct WTinyLfu<K> {
+    window: Queue<K>,
+    probation: Queue<K>,
+    protected: Queue<K>,
+    sketch: FrequencySketch,
+    window_cap: usize,
+    main_cap: usize,
+    protected_cap: usize,
+}
+
+impl<K: Clone + Hash + Eq> WTinyLfu<K> {
+    /// Create the policy.
+    pub fn new() -> Self {
+        WTinyLfu {
+            window: Queue::new(),
+            probation: Queue::new(),
+            protected: Queue::new(),
+            sketch: FrequencySketch::new(),
+            window_cap: 0,
+            main_cap: 0,
+            protected_cap: 0,
+        }
+    }
+
+    fn main_size(&self) -> usize {
+        self.probation.size + self.protected.size
+    }
+}
+
+impl<K: Clone + Hash + Eq> Default for WTinyLfu<K> {
+    fn default() -> Self {
+        WTinyLfu::new()
+    }
+}
+
+impl<K: Clone + Hash + Eq> EvictionPolicy<K> for WTinyLfu<K> {
+    fn set_capacity(&mut self, max_size: usize) {
+        self.window_cap = share(max_size, 1);
+        self.main_cap = max_size - self.window_cap;
+        self.protected_cap = share(self.main_cap, 80);
+        demote_overflow(&mut self.protected, &mut self.probation, self.protected_cap);
+    }
+
+    fn on_insert(&mut self, key: &K, size: usize) {
+        self.window.push(key.clone(), Node::new(size));
+
+        let tracked = self.window.len() + self.probation.len() + self.protected.len();
+        self.sketch.ensure_capacity(tracked);
+        self.sketch.increment(key);
+    }
+
+    fn on_access(&mut self, key: &K) {
+        self.sketch.increment(key);
+
+        if self.window.touch(key) || self.protected.touch(key) {
+            return;
+        }
+
+        if let Some(node) = self.probation.remove(key) {
+            self.protected.push(key.clone(), node);
+            demote_overflow(&mut self.protected, &mut self.probation, self.protected_cap);
+        }
+    }
+
+    fn on_resize(&mut self, key: &K, size: usize) {
+        if !self.window.resize(key, size)
+            && !self.probation.resize(key, size)
+            && self.protected.resize(key, size)
+        {
+            demote_overflow(&mut self.protected, &mut self.probation, self.protected_cap);
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if self.window.remove(key).is_none() && self.probation.remove(key).is_none() {
+            self.protected.remove(key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.window.clear();
+        self.probation.clear();
+        self.protected.clear();
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(&K) -> bool) -> Option<K> {
+        // drain the window into the main segments, admitting items while
+        // there is room and otherwise pitting them against the main victim.
+        // items which may not be evicted are admitted regardless.
+        while self.window.size > self.window_cap {
+            let (candidate, node) = match self.window.pop_oldest() {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            if self.main_size() + node.size <= self.main_cap || !evictable(&candidate) {
+                self.probation.push(candidate, node);
+                continue;
+            }
+
+            let victim = match self
+                .probation
+                .peek_oldest_where(evictable)
+                .or_else(|| self.protected.peek_oldest_where(evictable))
+            {
+                Some(victim) => victim.clone(),
+                None => return Some(candidate),
+            };
+
+            if self.sketch.frequency(&candidate) > self.sketch.frequency(&victim) {
+                if self.probation.remove(&victim).is_none() {
+                    self.protected.remove(&victim);
+                }
+                self.probation.push(candidate, node);
+                return Some(victim);
+            }
+
+            return Some(candidate);
+        }
+
+        self.probation
+            .pop_oldest_where(evictable)
+            .or_else(|| self.protected.pop_oldest_where(evictable))
+            .or_else(|| self.window.pop_oldest_where(evictable))
+            .map(|(key, _)| key)
+    }
+}
+
+/// The GreedyDual-Size policy, which evicts the item worth the least per byte.
+///
+/// Each item is given a priority of its cost divided by its size, on top of
+/// a baseline which rises to the priority of every evicted item. Accessing an
+/// item restores its priority against the current baseline, so items which
+/// are not used age out while small, costly or popular items are kept longer
+/// than large, cheap or cold ones.
+///
+/// The size is that charged by the cache's meter, which by default is the
+/// value's resident size. The cost of an item defaults to one, making the
+/// policy favour small items; supply a cost function to weigh in how
+/// expensive an item is to recompute.
+pub struct GreedyDualSize<K> {
+    cost: CostFn<K>,
+    baseline: f64,
+    queue: BTreeMap<(Priority, u64), K>,
+    entries: HashMap<K, Credit>,
+    // breaks ties between equal priorities in favour of evicting the older.
+    next_seq: u64,
+}
+
+type CostFn<K> = Box<dyn Fn(&K, usize) -> f64 + Send>;
+
+// an item's place in the queue, along with the size it was charged.
+#[derive(Debug, Clone, Copy)]
+struct Credit {
+    priority: Priority,
+    seq: u64,
+    size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Priority(f64);
+
+impl Eq for Priority {}
+
+impl PartialOrd for Priority {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Priority {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl<K: Clone + Hash + Eq> GreedyDualSize<K> {
+    /// Create the policy, giving every item a cost of one.
+    pub fn new() -> Self {
+        GreedyDualSize::with_cost(|_, _| 1.0)
+    }
+
+    /// Create the policy with a function giving the cost of recomputing an
+    /// item from its key and size.
+    pub fn with_cost(cost: impl Fn(&K, usize) -> f64 + Send + 'static) -> Self {
+        GreedyDualSize {
+            cost: Box::new(cost),
+            baseline: 0.0,
+            queue: BTreeMap::new(),
+            entries: HashMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    // (re)queue the key with a fresh priority.
+    fn credit(&mut self, key: &K, size: usize) {
+        if let Some(old) = self.entries.get(key) {
+            self.queue.remove(&(old.priority, old.seq));
+        }
+
+        // zero-sized items are worth their whole cost.
+        let priority = Priority(self.baseline + (self.cost)(key, size) / size.max(1) as f64);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.queue.insert((priority, seq), key.clone());
+        self.entries.insert(
+            key.clone(),
+            Credit {
+                priority,
+                seq,
+                size,
+            },
+        );
+    }
+}
+
+impl<K: Clone + Hash + Eq> Default for GreedyDualSize<K> {
+    fn default() -> Self {
+        GreedyDualSize::new()
+    }
+}
+
+impl<K: Clone + Hash + Eq> EvictionPolicy<K> for GreedyDualSize<K> {
+    fn set_capacity(&mut self, _max_size: usize) {}
+
+    fn on_insert(&mut self, key: &K, size: usize) {
+        self.credit(key, size);
+    }
+
+    fn on_access(&mut self, key: &K) {
+        if let Some(credit) = self.entries.get(key) {
+            let size = credit.size;
+            self.credit(key, size);
+        }
+    }
+
+    fn on_resize(&mut self, key: &K, size: usize) {
+        if self.entries.contains_key(key) {
+            self.credit(key, size);
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if let Some(credit) = self.entries.remove(key) {
+            self.queue.remove(&(credit.priority, credit.seq));
+        }
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+        self.entries.clear();
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(&K) -> bool) -> Option<K> {
+        let (&(priority, seq), _) = self.queue.iter().find(|(_, key)| evictable(key))?;
+        let key = self.queue.remove(&(priority, seq))?;
+        self.entries.remove(&key);
+        self.baseline = priority.0;
+        Some(key)
+    }
+}
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_MAX_COUNT: u8 = 15;
+
+// a count-min sketch estimating how often keys were seen. all counts are
+// halved once enough increments have been made, so that popularity fades.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    width: usize,
+    increments: usize,
+    hasher: RandomState,
+}
+
+impl FrequencySketch {
+    fn new() -> Self {
+        let width = 16;
+        FrequencySketch {
+            counters: vec![0; width * SKETCH_DEPTH],
+            width,
+            increments: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    // widen the sketch to suit the number of tracked keys. counts are lost.
+    fn ensure_capacity(&mut self, keys: usize) {
+        if keys <= self.width {
+            return;
+        }
+
+        self.width = keys.next_power_of_two();
+        self.counters = vec![0; self.width * SKETCH_DEPTH];
+        self.increments = 0;
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        let hash = self.hasher.hash_one(key);
+        for row in 0..SKETCH_DEPTH {
+            let index = self.index(hash, row);
+            let counter = &mut self.counters[index];
+            *counter = (*counter + 1).min(SKETCH_MAX_COUNT);
+        }
+
+        self.increments += 1;
+        if self.increments >= self.width * 10 {
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+            self.increments /= 2;
+        }
+    }
+
+    fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        let hash = self.hasher.hash_one(key);
+        (0..SKETCH_DEPTH)
+            .map(|row| self.counters[self.index(hash, row)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    // the counter for a key in the given row, derived from a differently
+    // mixed copy of the hash for each row.
+    fn index(&self, hash: u64, row: usize) -> usize {
+        const SEEDS: [u64; SKETCH_DEPTH] = [
+            0x9e37_79b9_7f4a_7c15,
+            0xc2b2_ae3d_27d4_eb4f,
+            0x1656_67b1_9e37_79f9,
+            0xff51_afd7_ed55_8ccd,
+        ];
+
+        let mixed = (hash ^ SEEDS[row]).wrapping_mul(SEEDS[(row + 1) % SKETCH_DEPTH]);
+        row * self.width + (mixed >> 32) as usize % self.width
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryLruCache;
+
+    // room for ten items. each round touches three hot items twice and then
+    // scans eight items never seen again, which is enough to flush an LRU.
+    // returns how often the first touch of a hot item hit.
+    fn hot_hits(policy: Option<Box<dyn EvictionPolicy<u32> + Send>>) -> usize {
+        let mut cache = MemoryLruCache::new(100);
+        if let Some(policy) = policy {
+            cache.set_eviction_policy(policy);
+        }
+
+        let mut hits = 0;
+        let mut scan = 1000;
+        for _ in 0..50 {
+            for hot in 0..3 {
+                if cache.get(&hot).is_some() {
+                    hits += 1;
+                } else {
+                    cache.insert(hot, vec![0u8; 10]);
+                }
+                cache.get(&hot);
+            }
+
+            for _ in 0..8 {
+                cache.insert(scan, vec![0u8; 10]);
+                scan += 1;
+            }
+            assert!(cache.current_size() <= 100);
+        }
+
+        hits
+    }
+
+    #[test]
+    fn policies_resist_scans() {
+        assert_eq!(hot_hits(None), 0);
+        assert!(hot_hits(Some(Box::new(Slru::new()))) > 140);
+        assert!(hot_hits(Some(Box::new(TwoQueue::new()))) > 140);
+        assert!(hot_hits(Some(Box::new(S3Fifo::new()))) > 140);
+        assert!(hot_hits(Some(Box::new(WTinyLfu::new()))) > 140);
+    }
+
+    #[test]
+    fn greedy_dual_size_weighs_cost_per_byte() {
+        let fill = |policy: GreedyDualSize<&'static str>| {
+            let mut cache = MemoryLruCache::new(100);
+            cache.set_eviction_policy(policy);
+            cache.insert("a", vec![0u8; 10]);
+            cache.insert("b", vec![0u8; 10]);
+            cache.insert("blob", vec![0u8; 80]);
+            cache.insert("c", vec![0u8; 5]);
+            cache.keys().copied().collect::<Vec<_>>()
+        };
+
+        // the blob is recently used, but the cheapest per byte.
+        assert_eq!(fill(GreedyDualSize::new()), vec!["c", "b", "a"]);
+
+        // unless it is costly enough to recompute.
+        let costly_blob = GreedyDualSize::with_cost(|k, _| if *k == "blob" { 1000.0 } else { 1.0 });
+        assert_eq!(fill(costly_blob), vec!["c", "blob", "b"]);
+    }
+
+    #[test]
+    fn policy_sees_existing_items_and_skips_pins() {
+        let mut cache = MemoryLruCache::new(30);
+        for i in 0..3u32 {
+            cache.insert(i, vec![0u8; 10]);
+        }
+        cache.set_eviction_policy(Slru::new());
+
+        // 0 is protected by the access and 1 by the pin, leaving 2.
+        cache.get(&0);
+        cache.pin(&1).unwrap();
+        cache.insert(3, vec![0u8; 10]);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec![3, 0, 1]);
+
+        assert_eq!(cache.remove(&3), Some(vec![0u8; 10]));
+        cache.insert(4, vec![0u8; 10]);
+        cache.insert(5, vec![0u8; 10]);
+        assert!(cache.contains(&0) && cache.contains(&1) && cache.contains(&5));
+        assert_eq!(cache.current_size(), 30);
+    }
+
+    #[test]
+    fn pinned_items_keep_their_place_in_the_policy() {
+        fn keys_after_pin(policy: impl EvictionPolicy<u32> + Send + 'static) -> Vec<u32> {
+            let mut cache = MemoryLruCache::new(30);
+            cache.set_eviction_policy(policy);
+            for i in 0..3u32 {
+                cache.insert(i, vec![0u8; 10]);
+            }
+
+            cache.pin(&0).unwrap();
+            cache.insert(3, vec![0u8; 10]);
+            cache.insert(3, vec![0u8; 10]);
+            assert!(cache.contains(&0));
+
+            cache.unpin(&0);
+            cache.insert(4, vec![0u8; 10]);
+            cache.keys().copied().collect()
+        }
+
+        // 0 is passed over while pinned and is the next victim once unpinned.
+        assert_eq!(keys_after_pin(Slru::new()), [4, 3, 2]);
+        assert_eq!(keys_after_pin(TwoQueue::new()), [4, 3, 2]);
+        assert_eq!(keys_after_pin(S3Fifo::new()), [4, 3, 2]);
+        assert_eq!(keys_after_pin(GreedyDualSize::new()), [4, 3, 2]);
+
+        // what follows the pin rests on the sketch's estimates.
+        keys_after_pin(WTinyLfu::new());
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Serde support for `MemoryLruCache`, enabled by the `serde` feature.
+//!
+//! A cache is written as its `max_size` along with its entries, from least to
+//! most recently used. Deserializing re-inserts the entries in that order, so
+//! the recency order is kept and the budget is applied afresh: if the values
+//! have grown or the budget no longer fits them, the least recently used
+//! entries are dropped.
+//!
+//! Time-to-live deadlines, pins, eviction listeners and other settings are not
+//! written, and expired entries are left out.
+
+use crate::{MemoryLruCache, Meter, ResidentSize, ValueSize};
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use std::hash::{BuildHasher, Hash};
+
+impl<K, V, M, S> Serialize for MemoryLruCache<K, V, M, S>
+where
+    K: Eq + Hash + Serialize,
+    V: Serialize,
+    M: Meter<K, V>,
+    S: BuildHasher,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let mut state = serializer.serialize_struct("MemoryLruCache", 2)?;
+        state.serialize_field("max_size", &self.max_size)?;
+        state.serialize_field("entries", &Entries(self))?;
+        state.end()
+    }
+}
+
+// the unexpired entries of a cache, from least to most recently used.
+struct Entries<'a, K, V, M, S>(&'a MemoryLruCache<K, V, M, S>);
+
+impl<'a, K, V, M, S> Serialize for Entries<'a, K, V, M, S>
+where
+    K: Eq + Hash + Serialize,
+    V: Serialize,
+    M: Meter<K, V>,
+    S: BuildHasher,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let cache = self.0;
+        serializer.collect_seq(
+            cache
+                .inner
+                .iter()
+                .rev()
+                .filter(|(_, slot)| !cache.is_expired(slot))
+                .map(|(k, slot)| (k, &slot.value)),
+        )
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename = "MemoryLruCache")]
+struct Contents<K, V> {
+    max_size: usize,
+    entries: Vec<(K, V)>,
+}
+
+impl<'de, K, V, S> Deserialize<'de> for MemoryLruCache<K, V, ValueSize, S>
+where
+    K: Eq + Hash + Deserialize<'de>,
+    V: ResidentSize + Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let contents = Contents::<K, V>::deserialize(deserializer)?;
+
+        let mut cache = MemoryLruCache::with_hasher(contents.max_size, S::default());
+        for (k, v) in contents.entries {
+            cache.insert(k, v);
+        }
+
+        Ok(cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryLruCache;
+
+    #[test]
+    fn round_trip_keeps_recency_order() {
+        let mut cache = MemoryLruCache::new(10);
+        for i in 0..4u8 {
+            cache.insert(i, vec![i; 2]);
+        }
+        cache.get(&1);
+
+        let json = serde_json::to_string(&cache).unwrap();
+        assert_eq!(
+            json,
+            r#"{"max_size":10,"entries":[[0,[0,0]],[2,[2,2]],[3,[3,3]],[1,[1,1]]]}"#
+        );
+
+        let restored: MemoryLruCache<u8, Vec<u8>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.max_size(), 10);
+        assert_eq!(restored.current_size(), 8);
+        assert_eq!(
+            restored.keys().copied().collect::<Vec<_>>(),
+            vec![1, 3, 2, 0]
+        );
+    }
+
+    #[test]
+    fn deserializing_applies_the_budget() {
+        let json = r#"{"max_size":5,"entries":[[0,[0,0]],[1,[1,1]],[2,[2,2]]]}"#;
+        let restored: MemoryLruCache<u8, Vec<u8>> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(restored.current_size(), 4);
+        assert_eq!(restored.keys().copied().collect::<Vec<_>>(), vec![2, 1]);
+    }
+}
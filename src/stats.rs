@@ -0,0 +1,146 @@
+// Copyright (c) 2015-2021 Parity Technologies
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Statistics about cache effectiveness, gathered when the `stats` feature
+//! is enabled.
+
+/// A snapshot of the statistics gathered by a `MemoryLruCache`.
+#[cfg(feature = "stats")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups through `get` or `with_mut` which found an item.
+    pub hits: u64,
+    /// Lookups through `get` or `with_mut` which found nothing.
+    pub misses: u64,
+    /// Items inserted, including those replacing an existing item.
+    pub insertions: u64,
+    /// Insertions which replaced an existing item under the same key.
+    pub replacements: u64,
+    /// Items evicted to keep the cache within its memory budget.
+    pub evictions: u64,
+    /// Total size of the evicted items in bytes.
+    pub evicted_bytes: u64,
+    /// The largest current size observed in bytes.
+    pub peak_size: usize,
+}
+
+// records statistics when enabled, and compiles down to nothing otherwise.
+#[derive(Default)]
+pub(crate) struct Recorder {
+    #[cfg(feature = "stats")]
+    stats: CacheStats,
+}
+
+#[cfg(feature = "stats")]
+impl Recorder {
+    pub(crate) fn snapshot(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    pub(crate) fn lookup(&mut self, hit: bool) {
+        if hit {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+    }
+
+    pub(crate) fn insertion(&mut self, replaced: bool) {
+        self.stats.insertions += 1;
+        if replaced {
+            self.stats.replacements += 1;
+        }
+    }
+
+    pub(crate) fn eviction(&mut self, size: usize) {
+        self.stats.evictions += 1;
+        self.stats.evicted_bytes += size as u64;
+    }
+
+    pub(crate) fn size(&mut self, cur_size: usize) {
+        self.stats.peak_size = self.stats.peak_size.max(cur_size);
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+impl Recorder {
+    #[inline(always)]
+    pub(crate) fn lookup(&mut self, _hit: bool) {}
+
+    #[inline(always)]
+    pub(crate) fn insertion(&mut self, _replaced: bool) {}
+
+    #[inline(always)]
+    pub(crate) fn eviction(&mut self, _size: usize) {}
+
+    #[inline(always)]
+    pub(crate) fn size(&mut self, _cur_size: usize) {}
+}
+
+#[cfg(all(test, feature = "stats"))]
+mod tests {
+    use crate::MemoryLruCache;
+
+    #[test]
+    fn it_works() {
+        let mut cache = MemoryLruCache::new(8);
+        cache.insert(1, vec![0u8; 4]);
+        cache.insert(1, vec![0u8; 6]);
+        cache.insert(2, vec![0u8; 2]);
+        cache.insert(3, vec![0u8; 3]);
+
+        assert!(cache.get(&1).is_none());
+        assert!(cache.get(&2).is_some());
+        cache.with_mut(&3, |v| v.unwrap().push(0));
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.insertions, 4);
+        assert_eq!(stats.replacements, 1);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.evicted_bytes, 6);
+        assert_eq!(stats.peak_size, 8);
+
+        cache.reset_stats();
+        assert_eq!(cache.stats(), Default::default());
+    }
+
+    #[test]
+    fn peak_size_sees_growth_in_place() {
+        let mut cache = MemoryLruCache::new(256);
+        cache.insert(1, vec![0u8; 10]);
+
+        for (_, v) in &mut cache.iter_mut() {
+            v.extend([0u8; 40]);
+        }
+        assert_eq!(cache.stats().peak_size, 50);
+
+        cache.retain(|_, v| {
+            v.extend([0u8; 45]);
+            true
+        });
+        assert_eq!(cache.stats().peak_size, 95);
+    }
+}